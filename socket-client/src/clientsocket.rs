@@ -1,34 +1,362 @@
 use crate::utils::*;
 use base64::{engine::general_purpose, Engine};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
 use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use std::vec::Vec;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
+use tokio_rustls::rustls::client::danger::{
+  HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{
+  ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{self, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio_rustls::TlsConnector;
 use tracing::{debug, error, info, warn};
 
+// Plain TCP or TLS, the rest of the socket only needs the byte stream
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> ClientStream for S {}
+
+type StreamReader = ReadHalf<Box<dyn ClientStream>>;
+type StreamWriter = WriteHalf<Box<dyn ClientStream>>;
+
 pub struct ClientSocket {
   server_uri: String,
   server_port: u16,
   server_path: String,
-  write_stream: Option<Arc<Mutex<OwnedWriteHalf>>>,
+  use_tls: bool,
+  root_ca_files: Vec<PathBuf>,
+  server_name: Option<String>,
+  insecure: bool,
+  // subprotocols to offer, most preferred first
+  protocols: Vec<String>,
+  // the one the server picked
+  protocol: Option<String>,
+  // offer permessage-deflate in the handshake
+  compression: bool,
+  deflate: Option<DeflateParams>,
+  deflater: Option<Deflater>,
+  write_stream: Option<Arc<Mutex<StreamWriter>>>,
   reader_thread: Option<JoinHandle<()>>,
+  received: Option<Receiver<Payload>>,
+  // messages kept for read_message, newer ones are dropped while it is full
+  receive_buffer: usize,
+  closing: Arc<AtomicBool>,
+  close_reason: Arc<Mutex<Option<CloseReason>>>,
+  close_timeout: Duration,
+  // largest payload a frame from the server may declare
+  max_frame_size: usize,
+  // largest message from the server once decompressed
+  max_message_size: usize,
   mask_key: Vec<u8>,
   connected: bool,
 }
 
+#[derive(Debug, Clone)]
+pub struct CloseReason {
+  pub code: u16,
+  pub reason: String,
+}
+
+impl CloseReason {
+  pub fn new(code: u16, reason: &str) -> CloseReason {
+    CloseReason {
+      code,
+      reason: String::from(reason),
+    }
+  }
+}
+
+impl fmt::Display for CloseReason {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.reason.is_empty() {
+      write!(f, "{}", self.code)
+    } else {
+      write!(f, "{} ({})", self.code, self.reason)
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub enum Payload {
+  Text(String),
+  Binary(Vec<u8>),
+}
+
+// Splits a ws:// or wss:// URL into whether it uses TLS, the host, port and path. Without a scheme
+// the URL is taken as plain ws, so "localhost:8080/chat" still works.
+fn parse_uri(uri: &str) -> Option<(bool, String, u16, String)> {
+  let (use_tls, rest) = if let Some(rest) = uri.strip_prefix("wss://") {
+    (true, rest)
+  } else if let Some(rest) = uri.strip_prefix("ws://") {
+    (false, rest)
+  } else if uri.contains("://") {
+    return None;
+  } else {
+    (false, uri)
+  };
+  let (authority, path) = match rest.find('/') {
+    Some(pos) => (&rest[..pos], &rest[pos..]),
+    None => (rest, "/"),
+  };
+  let default_port: u16 = if use_tls { 443 } else { 80 };
+  // IPv6 hosts come in brackets so their colons aren't mistaken for the port
+  let (host, port) = match authority.strip_prefix('[') {
+    Some(bracketed) => {
+      let (host, after) = bracketed.split_once(']')?;
+      match after.strip_prefix(':') {
+        Some(port) => (host, port.parse::<u16>().ok()?),
+        None if after.is_empty() => (host, default_port),
+        None => return None,
+      }
+    }
+    None => match authority.rsplit_once(':') {
+      Some((host, port)) => (host, port.parse::<u16>().ok()?),
+      None => (authority, default_port),
+    },
+  };
+  if host.is_empty() {
+    return None;
+  }
+  Some((use_tls, String::from(host), port, String::from(path)))
+}
+
+const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+// every compressed message ends with an empty stored block that is left off on the wire
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// What the server agreed to in its Sec-WebSocket-Extensions header (RFC 7692)
+#[derive(Debug, Clone)]
+struct DeflateParams {
+  server_no_context_takeover: bool,
+  client_no_context_takeover: bool,
+  client_max_window_bits: u8,
+}
+
+// None if the server answered with something we did not offer
+fn parse_extensions(extensions: &str) -> Option<DeflateParams> {
+  let mut params = extensions.split(';').map(|param| param.trim());
+  if params.next()? != PERMESSAGE_DEFLATE {
+    return None;
+  }
+  let mut agreed = DeflateParams {
+    server_no_context_takeover: false,
+    client_no_context_takeover: false,
+    client_max_window_bits: 15,
+  };
+  for param in params {
+    let (name, value) = match param.split_once('=') {
+      Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+      None => (param, None),
+    };
+    match (name, value) {
+      ("server_no_context_takeover", None) => agreed.server_no_context_takeover = true,
+      ("client_no_context_takeover", None) => agreed.client_no_context_takeover = true,
+      // the server's window only matters to the server, we always read with the largest one
+      ("server_max_window_bits", Some(bits)) => {
+        bits
+          .parse::<u8>()
+          .ok()
+          .filter(|bits| (8..=15).contains(bits))?;
+      }
+      // zlib can't write raw deflate with an 8 bit window
+      ("client_max_window_bits", Some(bits)) => {
+        agreed.client_max_window_bits = bits
+          .parse::<u8>()
+          .ok()
+          .filter(|bits| (9..=15).contains(bits))?;
+      }
+      _ => return None,
+    }
+  }
+  Some(agreed)
+}
+
+struct Deflater {
+  compress: Compress,
+  no_context_takeover: bool,
+}
+
+impl Deflater {
+  fn new(params: &DeflateParams) -> Deflater {
+    Deflater {
+      compress: Compress::new_with_window_bits(
+        Compression::default(),
+        false,
+        params.client_max_window_bits,
+      ),
+      no_context_takeover: params.client_no_context_takeover,
+    }
+  }
+
+  fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(data.len() / 2 + 64);
+    let start = self.compress.total_in();
+    loop {
+      let consumed = (self.compress.total_in() - start) as usize;
+      if out.len() == out.capacity() {
+        out.reserve(out.capacity());
+      }
+      self
+        .compress
+        .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+        .expect("deflate failed");
+      if (self.compress.total_in() - start) as usize == data.len() && out.len() < out.capacity() {
+        break;
+      }
+    }
+    if out.ends_with(&DEFLATE_TAIL) {
+      out.truncate(out.len() - DEFLATE_TAIL.len());
+    }
+    if self.no_context_takeover {
+      self.compress.reset();
+    }
+    out
+  }
+}
+
+struct Inflater {
+  decompress: Decompress,
+  no_context_takeover: bool,
+  max_message_size: usize,
+}
+
+impl Inflater {
+  fn new(params: &DeflateParams, max_message_size: usize) -> Inflater {
+    Inflater {
+      decompress: Decompress::new_with_window_bits(false, 15),
+      no_context_takeover: params.server_no_context_takeover,
+      max_message_size,
+    }
+  }
+
+  // Fails with the close code to answer with if the server sent something that isn't valid
+  // deflate or inflates past the message size limit
+  fn decompress(&mut self, data: &[u8]) -> Result<Vec<u8>, u16> {
+    let mut input: Vec<u8> = data.to_vec();
+    input.extend_from_slice(&DEFLATE_TAIL);
+    let mut out: Vec<u8> = Vec::with_capacity((data.len() * 2).min(self.max_message_size).max(64));
+    let start = self.decompress.total_in();
+    loop {
+      let consumed = (self.decompress.total_in() - start) as usize;
+      if out.len() == out.capacity() {
+        out.reserve(out.capacity());
+      }
+      let written = out.len();
+      let status = self
+        .decompress
+        .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+        .map_err(|_| CLOSE_PROTOCOL_ERROR)?;
+      if out.len() > self.max_message_size {
+        return Err(CLOSE_MESSAGE_TOO_BIG);
+      }
+      let consumed_now = (self.decompress.total_in() - start) as usize;
+      if status == Status::StreamEnd {
+        self.decompress.reset(false);
+        break;
+      }
+      if consumed_now == input.len() && out.len() < out.capacity() {
+        break;
+      }
+      if consumed_now == consumed && out.len() == written && out.len() < out.capacity() {
+        return Err(CLOSE_PROTOCOL_ERROR);
+      }
+    }
+    if self.no_context_takeover {
+      self.decompress.reset(false);
+    }
+    Ok(out)
+  }
+}
+
+fn read_certificates(path: &Path) -> std::io::Result<Vec<CertificateDer<'static>>> {
+  let file = File::open(path)?;
+  rustls_pemfile::certs(&mut BufReader::new(file)).collect()
+}
+
+// Accepts any server certificate. Only meant for testing against self-signed certificates,
+// handshake signatures are still checked so the connection itself works as usual.
+#[derive(Debug)]
+struct NoCertificateVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoCertificateVerification {
+  fn verify_server_cert(
+    &self,
+    _end_entity: &CertificateDer<'_>,
+    _intermediates: &[CertificateDer<'_>],
+    _server_name: &ServerName<'_>,
+    _ocsp_response: &[u8],
+    _now: UnixTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    Ok(ServerCertVerified::assertion())
+  }
+
+  fn verify_tls12_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    verify_tls12_signature(
+      message,
+      cert,
+      dss,
+      &self.0.signature_verification_algorithms,
+    )
+  }
+
+  fn verify_tls13_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    verify_tls13_signature(
+      message,
+      cert,
+      dss,
+      &self.0.signature_verification_algorithms,
+    )
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self.0.signature_verification_algorithms.supported_schemes()
+  }
+}
+
 fn generate_key() -> String {
   // Random 16 byte value base-64 encoded
-  let bytes: String = (0..16).map(|_| rand::random::<u8>() as char).collect();
+  let bytes: Vec<u8> = (0..16).map(|_| rand::random::<u8>()).collect();
   general_purpose::STANDARD.encode(&bytes)
 }
 
-fn pack_message_frame(payload: String, masking_key: &Vec<u8>) -> Vec<u8> {
-  // FIN = 1 (only frame in message), RSV1-3 = 0, opcode = 0001 (text frame)
-  let mut frame: Vec<u8> = vec![0b10000001];
+fn pack_message_frame(opcode: u8, payload: &[u8], masking_key: &Vec<u8>) -> Vec<u8> {
+  // FIN = 1 (only frame in message), RSV1-3 = 0
+  pack_frame_with_first_byte(0b10000000 + opcode, payload, masking_key)
+}
+
+// Same as above with RSV1 set to mark a permessage-deflate compressed message
+fn pack_compressed_frame(opcode: u8, payload: &[u8], masking_key: &Vec<u8>) -> Vec<u8> {
+  pack_frame_with_first_byte(0b11000000 + opcode, payload, masking_key)
+}
+
+fn pack_frame_with_first_byte(first_byte: u8, payload: &[u8], masking_key: &Vec<u8>) -> Vec<u8> {
+  let mut frame: Vec<u8> = vec![first_byte];
   frame.reserve(1024);
 
   let mut second_byte: u8 = 128; // set mask bit
@@ -56,106 +384,288 @@ fn pack_message_frame(payload: String, masking_key: &Vec<u8>) -> Vec<u8> {
   }
   frame.extend_from_slice(&masking_key);
   let payload_start = frame.len();
-  frame.extend_from_slice(payload.as_bytes());
+  frame.extend_from_slice(payload);
   for i in 0..(strlen as usize) {
     frame[i + payload_start] ^= masking_key[i % 4];
   }
   frame
 }
 
-fn unpack_server_frame(buf: &mut Vec<u8>) -> (Option<u8>, Option<String>) {
+// Recipients go in front of binary messages as a big-endian u16 count followed by big-endian u32
+// client ids
+fn pack_binary_message(recipients: &[usize], data: &[u8]) -> std::io::Result<Vec<u8>> {
+  let invalid = |err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err);
+  let count = u16::try_from(recipients.len()).map_err(invalid)?;
+  let mut message: Vec<u8> = Vec::with_capacity(2 + recipients.len() * 4 + data.len());
+  message.extend_from_slice(&count.to_be_bytes());
+  for recipient in recipients {
+    let recipient = u32::try_from(*recipient).map_err(invalid)?;
+    message.extend_from_slice(&recipient.to_be_bytes());
+  }
+  message.extend_from_slice(data);
+  Ok(message)
+}
+
+fn pack_close_payload(close: &CloseReason) -> Vec<u8> {
+  if close.code == CLOSE_NO_STATUS {
+    return Vec::new();
+  }
+  let mut payload: Vec<u8> = close.code.to_be_bytes().to_vec();
+  // control frames are capped at 125 bytes, leaving 123 for the reason
+  let mut reason_len = close.reason.len().min(123);
+  while !close.reason.is_char_boundary(reason_len) {
+    reason_len -= 1;
+  }
+  payload.extend_from_slice(&close.reason.as_bytes()[..reason_len]);
+  payload
+}
+
+fn unpack_close_payload(payload: &[u8]) -> Result<CloseReason, u16> {
+  if payload.is_empty() {
+    return Ok(CloseReason::new(CLOSE_NO_STATUS, ""));
+  }
+  if payload.len() == 1 {
+    return Err(CLOSE_PROTOCOL_ERROR);
+  }
+  let code = u16::from_be_bytes([payload[0], payload[1]]);
+  if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+    return Err(CLOSE_PROTOCOL_ERROR);
+  }
+  let reason = String::from_utf8(payload[2..].to_vec()).map_err(|_| CLOSE_INVALID_DATA)?;
+  Ok(CloseReason { code, reason })
+}
+
+// Takes the first whole frame off the front of buf. Ok(None) while more bytes are needed, Err with
+// the close code to answer with when the frame is invalid or too big.
+fn unpack_server_frame(
+  buf: &mut Vec<u8>,
+  max_frame_size: usize,
+  max_message_size: usize,
+  inflater: &mut Option<Inflater>,
+) -> Result<Option<(u8, Option<Payload>)>, u16> {
+  if buf.len() < 2 {
+    return Ok(None);
+  }
   let first_byte = buf[0];
   let fin: bool = (first_byte & 128) >> 7 == 1;
   if !fin {
     // change
-    return (None, None);
-  }
-  let rsv: u8 = first_byte & 0b01110000;
-  if rsv != 0 {
-    return (None, None);
+    return Err(CLOSE_PROTOCOL_ERROR);
   }
   let opcode: u8 = first_byte & 15;
-  if opcode != 1 {
-    // text frame, change this later
-    return (Some(opcode), None);
+  if !matches!(opcode, 0x1 | 0x2 | 0x8 | 0x9 | 0xA) {
+    return Err(CLOSE_PROTOCOL_ERROR);
+  }
+  // RSV1 marks a compressed data frame, and only once deflate was negotiated
+  let compressed: bool = first_byte & 0b01000000 != 0;
+  if first_byte & 0b00110000 != 0 || (compressed && (inflater.is_none() || opcode & 0x8 != 0)) {
+    return Err(CLOSE_PROTOCOL_ERROR);
+  }
+  if opcode & 0x8 != 0 && buf[1] & 127 > 125 {
+    // control frames always fit in the second byte's length
+    return Err(CLOSE_PROTOCOL_ERROR);
   }
 
   let second_byte = buf[1];
   let mask: bool = (second_byte & 128) >> 7 == 1;
   if mask {
     // servers must not mask stuff
-    return (None, None);
+    return Err(CLOSE_PROTOCOL_ERROR);
   }
   let second_byte_payload_len = second_byte & 127;
-  let mut payload_len: usize = second_byte_payload_len as usize;
+  let mut payload_len: u64 = second_byte_payload_len as u64;
   let mut payload_len_bytes: usize = 0;
   if second_byte_payload_len == 127 {
     payload_len_bytes = 8;
-    payload_len = u64::from_be_bytes(buf[2..10].try_into().unwrap()) as usize;
   } else if second_byte_payload_len == 126 {
     payload_len_bytes = 2;
-    payload_len = u16::from_be_bytes(buf[2..4].try_into().unwrap()) as usize;
   }
-
   let payload_start = payload_len_bytes + 2;
+  if buf.len() < payload_start {
+    return Ok(None);
+  }
+  if payload_len_bytes == 8 {
+    payload_len = u64::from_be_bytes(buf[2..10].try_into().unwrap());
+  } else if payload_len_bytes == 2 {
+    payload_len = u16::from_be_bytes(buf[2..4].try_into().unwrap()) as u64;
+  }
+  // the length comes from the server, so it is checked before anything waits for that much
+  if payload_len >> 63 != 0 {
+    return Err(CLOSE_PROTOCOL_ERROR);
+  }
+  if payload_len > max_frame_size as u64 || (!compressed && payload_len > max_message_size as u64) {
+    return Err(CLOSE_MESSAGE_TOO_BIG);
+  }
+  if ((buf.len() - payload_start) as u64) < payload_len {
+    return Ok(None);
+  }
 
-  let payload = &mut buf[payload_start..payload_start + payload_len];
-  let s = (*String::from_utf8_lossy(payload)).to_string();
-
-  (Some(opcode), Some(s))
+  let frame_end = payload_start + payload_len as usize;
+  let payload: Vec<u8> = match inflater {
+    Some(inflater) if compressed => inflater.decompress(&buf[payload_start..frame_end])?,
+    _ => buf[payload_start..frame_end].to_vec(),
+  };
+  buf.drain(..frame_end);
+  if opcode != 0x1 {
+    // binary and control frame payloads are handed back as is
+    return Ok(Some((opcode, Some(Payload::Binary(payload)))));
+  }
+  match String::from_utf8(payload) {
+    Ok(s) => Ok(Some((opcode, Some(Payload::Text(s))))),
+    // text frame without a payload means it was not valid UTF-8
+    Err(_) => Ok(Some((opcode, None))),
+  }
 }
 
 impl ClientSocket {
   pub fn new(uri: String) -> ClientSocket {
-    let split_uri: std::vec::Vec<&str> = uri.split(':').collect();
-    let port_path = String::from(split_uri[1]);
-    let port_path_vec: Vec<&str> = port_path.split('/').collect();
-    let mut path = String::from("/");
-    if port_path_vec.len() > 1 {
-      path = String::from("/") + port_path_vec[1];
-    }
-    let server_uri = String::from(split_uri[0]);
-    let server_port = port_path_vec[0].parse::<u16>().unwrap();
+    let (use_tls, server_uri, server_port, path) = parse_uri(&uri).expect("Invalid server URI");
     info!(
-      "Server URI: {} Port: {} Path: {}",
-      server_uri, server_port, path
+      "Server URI: {} Port: {} Path: {} TLS: {}",
+      server_uri, server_port, path, use_tls
     );
     ClientSocket {
-      server_uri: server_uri.clone(),
+      server_uri,
       server_port,
       server_path: path,
+      use_tls,
+      root_ca_files: Vec::new(),
+      server_name: None,
+      insecure: false,
+      protocols: Vec::new(),
+      protocol: None,
+      compression: true,
+      deflate: None,
+      deflater: None,
       write_stream: None,
       reader_thread: None,
+      received: None,
+      receive_buffer: DEFAULT_RECEIVE_BUFFER,
+      closing: Arc::new(AtomicBool::new(false)),
+      close_reason: Arc::new(Mutex::new(None)),
+      close_timeout: Duration::from_millis(DEFAULT_CLOSE_TIMEOUT),
+      max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+      max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
       mask_key: vec![0; 4],
       connected: false,
     }
   }
 
+  // Trusts the CA certificates in this PEM file on top of the usual web roots for wss
+  pub fn add_root_certificates(&mut self, path: &Path) {
+    self.root_ca_files.push(path.to_path_buf());
+  }
+
+  // Name to send as SNI and check the certificate against, defaults to the host in the URI
+  pub fn set_server_name(&mut self, server_name: String) {
+    self.server_name = Some(server_name);
+  }
+
+  // Skips certificate verification, only for testing against self-signed servers
+  pub fn set_insecure(&mut self, insecure: bool) {
+    self.insecure = insecure;
+  }
+
+  // Subprotocols to offer in Sec-WebSocket-Protocol, most preferred first
+  pub fn set_protocols(&mut self, protocols: Vec<String>) {
+    self.protocols = protocols;
+  }
+
+  // The subprotocol the server picked, None before connecting or if it picked none
+  pub fn protocol(&self) -> Option<&str> {
+    self.protocol.as_deref()
+  }
+
+  // Offers permessage-deflate to the server, on by default
+  pub fn set_compression(&mut self, compression: bool) {
+    self.compression = compression;
+  }
+
+  fn tls_connector(&self) -> std::io::Result<TlsConnector> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(Arc::clone(&provider))
+      .with_safe_default_protocol_versions()
+      .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let config = if self.insecure {
+      warn!("Not verifying the server certificate");
+      builder
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(NoCertificateVerification(provider)))
+        .with_no_client_auth()
+    } else {
+      let mut roots = RootCertStore::empty();
+      roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+      for path in &self.root_ca_files {
+        for cert in read_certificates(path)? {
+          roots
+            .add(cert)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        }
+      }
+      builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+  }
+
+  // Runs the TLS handshake for wss, otherwise hands the socket back as is
+  async fn open_stream(&self, stream: TcpStream) -> std::io::Result<Box<dyn ClientStream>> {
+    if !self.use_tls {
+      return Ok(Box::new(stream));
+    }
+    let name = self.server_name.as_ref().unwrap_or(&self.server_uri);
+    let server_name = ServerName::try_from(name.clone())
+      .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let tls_stream = self.tls_connector()?.connect(server_name, stream).await?;
+    Ok(Box::new(tls_stream))
+  }
+
   async fn handshake_http(
     &mut self,
-    read_half: &mut OwnedReadHalf,
-    write_half: &mut OwnedWriteHalf,
+    read_half: &mut StreamReader,
+    write_half: &mut StreamWriter,
+    my_addr: SocketAddr,
   ) -> bool {
     //dGhlIHNhbXBsZSBub25jZQ==
     let mut buf = vec![0; 1024];
-    let my_addr: std::net::SocketAddr = read_half.local_addr().unwrap();
     let my_key: String = generate_key();
+    // IPv6 hosts need their brackets back
+    let host = if self.server_uri.contains(':') {
+      format!("[{}]", self.server_uri)
+    } else {
+      self.server_uri.clone()
+    };
+    let protocols = if self.protocols.is_empty() {
+      String::new()
+    } else {
+      format!("Sec-WebSocket-Protocol: {}\r\n", self.protocols.join(", "))
+    };
+    let extensions = if self.compression {
+      format!(
+        "Sec-WebSocket-Extensions: {}; client_max_window_bits\r\n",
+        PERMESSAGE_DEFLATE
+      )
+    } else {
+      String::new()
+    };
     let handshake = format!(
       "GET {} HTTP/1.1\r\n\
-      Host: {}:{}]\r\n\
+      Host: {}:{}\r\n\
       Upgrade: websocket\r\n\
       Connection: Upgrade\r\n\
       Sec-WebSocket-Key: {}\r\n\
       Origin: {}:{}\r\n\
-      Sec-WebSocket-Version: 13\r\n\r\n",
+      Sec-WebSocket-Version: 13\r\n\
+      {}{}\r\n",
       self.server_path,
-      self.server_uri,
+      host,
       self.server_port,
       &my_key,
       my_addr.ip().to_string(),
       my_addr.port().to_string(),
+      protocols,
+      extensions,
     );
-    match write_half.write(handshake.as_bytes()).await {
+    match write_half.write_all(handshake.as_bytes()).await {
       Ok(_) => {
         info!("Sent handshake");
       }
@@ -175,6 +685,8 @@ impl ClientSocket {
           (String::from("Upgrade"), None),
           (String::from("Connection"), None),
           (String::from("Sec-WebSocket-Accept"), None),
+          (String::from("Sec-WebSocket-Protocol"), None),
+          (String::from("Sec-WebSocket-Extensions"), None),
         ]);
         for line in lines[1..].iter() {
           let split_line: Vec<&str> = (*line).trim().split(": ").collect();
@@ -203,6 +715,26 @@ impl ClientSocket {
         if upgrade != "websocket" || connection != "Upgrade" || swk != expected_key {
           return false;
         }
+        self.protocol = m.get("Sec-WebSocket-Protocol").unwrap().clone();
+        if let Some(protocol) = &self.protocol {
+          // the server has to pick one of ours or none at all
+          if !self.protocols.contains(protocol) {
+            warn!(
+              "Server picked subprotocol {} which was not offered",
+              protocol
+            );
+            return false;
+          }
+        }
+        self.deflate = match m.get("Sec-WebSocket-Extensions").unwrap() {
+          // the server may only accept extensions we offered
+          Some(extensions) if self.compression => match parse_extensions(extensions) {
+            Some(params) => Some(params),
+            None => return false,
+          },
+          Some(_) => return false,
+          None => None,
+        };
       }
       Err(e) => {
         error!("Failed to receive data: {}", e);
@@ -213,8 +745,19 @@ impl ClientSocket {
     true
   }
 
-  async fn reader_loop(read_stream: &mut OwnedReadHalf, write_stream: &Arc<Mutex<OwnedWriteHalf>>) {
+  // Runs until the connection closes and returns why it did
+  async fn reader_loop(
+    read_stream: &mut StreamReader,
+    write_stream: &Arc<Mutex<StreamWriter>>,
+    received: Sender<Payload>,
+    closing: &AtomicBool,
+    mut inflater: Option<Inflater>,
+    max_frame_size: usize,
+    max_message_size: usize,
+  ) -> CloseReason {
     let mut buf = vec![0; 1024];
+    // what has been read but doesn't make up a whole frame yet
+    let mut pending: Vec<u8> = Vec::new();
     loop {
       match read_stream.read(&mut buf).await {
         Ok(size) => {
@@ -222,31 +765,70 @@ impl ClientSocket {
             debug!("size is 0");
             break;
           }
-          let (opcode, payload) = unpack_server_frame(&mut buf);
-          match opcode {
-            None => {
-              break;
-            }
-            Some(opcode_val) => {
-              if opcode_val == 0x8 {
-                // send a closing frame too if you have not already sent one
-                debug!("client received close frame");
-                break;
-              } else if opcode_val == 0x9 {
-                // ping, send pong
-                // send control frame of 0xA
-                Self::send_control_frame(write_stream, 0xA).await;
-                debug!("client received ping");
-              } else if opcode_val == 0x1 {
-                match payload {
-                  None => {}
-                  Some(msg) => {
-                    debug!("client received message: {}", msg);
+          pending.extend_from_slice(&buf[..size]);
+          loop {
+            let (opcode_val, payload) = match unpack_server_frame(
+              &mut pending,
+              max_frame_size,
+              max_message_size,
+              &mut inflater,
+            ) {
+              Ok(Some(frame)) => frame,
+              Ok(None) => break,
+              Err(code) => {
+                let reason = match code {
+                  CLOSE_MESSAGE_TOO_BIG => "Message too big",
+                  _ => "Invalid frame",
+                };
+                let close = CloseReason::new(code, reason);
+                Self::send_close_frame(write_stream, &close).await;
+                return close;
+              }
+            };
+            if opcode_val == 0x8 {
+              debug!("client received close frame");
+              let close_payload = match payload {
+                Some(Payload::Binary(close_payload)) => close_payload,
+                _ => Vec::new(),
+              };
+              match unpack_close_payload(&close_payload) {
+                Ok(close) => {
+                  // echo the server's close frame unless this side started the close
+                  if !closing.swap(true, Ordering::SeqCst) {
+                    Self::send_close_frame(write_stream, &close).await;
+                  }
+                  return close;
+                }
+                Err(code) => {
+                  let close = CloseReason::new(code, "Invalid close frame");
+                  Self::send_close_frame(write_stream, &close).await;
+                  return close;
+                }
+              }
+            } else if opcode_val == 0x9 {
+              // ping, send pong with the same application data
+              let ping_payload = match payload {
+                Some(Payload::Binary(ping_payload)) => ping_payload,
+                _ => Vec::new(),
+              };
+              Self::send_control_frame(write_stream, 0xA, &ping_payload).await;
+              debug!("client received ping");
+            } else if opcode_val == 0xA {
+              debug!("client received pong");
+            } else {
+              match payload {
+                None => {
+                  warn!("client received invalid UTF-8 text frame");
+                  let close = CloseReason::new(CLOSE_INVALID_DATA, "Invalid UTF-8");
+                  Self::send_close_frame(write_stream, &close).await;
+                  return close;
+                }
+                Some(msg) => {
+                  debug!("client received message: {:?}", msg);
+                  if let Err(TrySendError::Full(_)) = received.try_send(msg) {
+                    warn!("Receive buffer is full, dropping a message from the server");
                   }
                 }
-              } else {
-                // handle pings and pongs
-                break;
               }
             }
           }
@@ -257,23 +839,22 @@ impl ClientSocket {
         }
       }
     }
+    // the connection went away without a close frame
+    CloseReason::new(CLOSE_ABNORMAL, "")
   }
 
-  pub async fn write_message(&mut self, recipients: Vec<usize>, msg: String) {
+  async fn write_frame(&mut self, opcode: u8, payload: &[u8]) {
     if !self.connected {
       panic!("Client not connected");
     }
-    let mut combined_msg = recipients
-      .iter()
-      .map(|x| x.to_string() + ",")
-      .collect::<String>();
-    combined_msg += &msg;
-
-    let byte_msg = pack_message_frame(combined_msg.clone(), &self.mask_key);
+    let byte_msg = match &mut self.deflater {
+      Some(deflater) => pack_compressed_frame(opcode, &deflater.compress(payload), &self.mask_key),
+      None => pack_message_frame(opcode, payload, &self.mask_key),
+    };
     let mut stream = self.write_stream.as_mut().unwrap().lock().await;
-    match stream.write(&byte_msg).await {
+    match stream.write_all(&byte_msg).await {
       Ok(_) => {
-        debug!("Client sent message: {}", msg);
+        debug!("Client sent message of {} bytes", payload.len());
       }
       Err(e) => {
         error!("Error writing from client: {}", e);
@@ -281,20 +862,78 @@ impl ClientSocket {
     }
   }
 
-  pub async fn connect(&mut self, id: u32) {
-    let address: String = format!("{}:{}", self.server_uri, self.server_port);
-    info!("Connecting to {}", address);
-    match TcpStream::connect(address).await {
+  pub async fn write_message(&mut self, recipients: Vec<usize>, msg: String) {
+    let mut combined_msg = recipients
+      .iter()
+      .map(|x| x.to_string() + ",")
+      .collect::<String>();
+    combined_msg += &msg;
+    self.write_frame(0x1, combined_msg.as_bytes()).await;
+  }
+
+  // Fails without sending anything when there are too many recipients or an id doesn't fit in the
+  // binary format
+  pub async fn write_binary(&mut self, recipients: Vec<usize>, data: &[u8]) -> std::io::Result<()> {
+    let combined_msg = pack_binary_message(&recipients, data)?;
+    self.write_frame(0x2, &combined_msg).await;
+    Ok(())
+  }
+
+  // Waits for the next text or binary message from the server, None once the connection closes
+  pub async fn read_message(&mut self) -> Option<Payload> {
+    self.received.as_mut()?.recv().await
+  }
+
+  // Connects and registers with a token signed with the server key, see sign_token
+  pub async fn connect(&mut self, token: &str) {
+    info!("Connecting to {}:{}", self.server_uri, self.server_port);
+    let stream = match TcpStream::connect((self.server_uri.as_str(), self.server_port)).await {
+      Ok(stream) => stream,
+      Err(e) => {
+        error!("Failed to connect stream: {}", e);
+        return;
+      }
+    };
+    let my_addr: SocketAddr = stream.local_addr().unwrap();
+    match self.open_stream(stream).await {
       Ok(stream) => {
-        let (mut read_half, mut write_half) = stream.into_split();
-        self.connected = self.handshake_http(&mut read_half, &mut write_half).await;
+        let (mut read_half, mut write_half) = tokio::io::split(stream);
+        self.connected = self
+          .handshake_http(&mut read_half, &mut write_half, my_addr)
+          .await;
         if self.connected {
+          if let Some(params) = &self.deflate {
+            info!("Using {}: {:?}", PERMESSAGE_DEFLATE, params);
+          }
+          self.deflater = self.deflate.as_ref().map(Deflater::new);
+          let inflater = self
+            .deflate
+            .as_ref()
+            .map(|params| Inflater::new(params, self.max_message_size));
+          let (max_frame_size, max_message_size) = (self.max_frame_size, self.max_message_size);
           self.write_stream = Some(Arc::new(Mutex::new(write_half)));
           info!("Connected to server in port {}", self.server_port);
-          self.write_message(Vec::new(), id.to_string()).await;
+          self.write_frame(0x1, token.as_bytes()).await;
           let stream_clone = Arc::clone(&self.write_stream.as_ref().unwrap());
+          let (received_tx, received_rx) = mpsc::channel(self.receive_buffer);
+          self.received = Some(received_rx);
+          self.closing.store(false, Ordering::SeqCst);
+          *self.close_reason.lock().await = None;
+          let closing = Arc::clone(&self.closing);
+          let close_reason = Arc::clone(&self.close_reason);
           self.reader_thread = Some(tokio::spawn(async move {
-            Self::reader_loop(&mut read_half, &stream_clone).await
+            let close = Self::reader_loop(
+              &mut read_half,
+              &stream_clone,
+              received_tx,
+              &closing,
+              inflater,
+              max_frame_size,
+              max_message_size,
+            )
+            .await;
+            info!("Connection closed: {}", close);
+            *close_reason.lock().await = Some(close);
           }));
           for i in 0..4 {
             self.mask_key[i] = rand::random::<u8>()
@@ -304,38 +943,370 @@ impl ClientSocket {
         }
       }
       Err(e) => {
-        error!("Failed to connect stream: {}", e);
+        error!("Failed to set up TLS: {}", e);
       }
     }
   }
 
-  async fn send_control_frame(write_stream: &Arc<Mutex<OwnedWriteHalf>>, opcode: u8) {
-    let byte_msg: Vec<u8> = vec![0b10000000 + opcode];
+  async fn send_control_frame(write_stream: &Arc<Mutex<StreamWriter>>, opcode: u8, payload: &[u8]) {
+    let masking_key: Vec<u8> = (0..4).map(|_| rand::random::<u8>()).collect();
+    let byte_msg = pack_message_frame(opcode, payload, &masking_key);
     let mut stream = write_stream.lock().await;
-    match stream.write(&byte_msg).await {
+    match stream.write_all(&byte_msg).await {
       Ok(_) => {
         debug!("Client sent opcode {}", opcode);
       }
       Err(_) => {
-        error!("Failed to send client contorl frame of code {}", opcode);
+        error!("Failed to send client control frame of code {}", opcode);
       }
     }
   }
 
-  pub async fn disconnect(&mut self) {
-    Self::send_control_frame(self.write_stream.as_mut().unwrap(), 8).await;
+  async fn send_close_frame(write_stream: &Arc<Mutex<StreamWriter>>, close: &CloseReason) {
+    Self::send_control_frame(write_stream, 0x8, &pack_close_payload(close)).await;
+    debug!("Client sent close frame {}", close);
+  }
+
+  pub fn set_close_timeout(&mut self, close_timeout: Duration) {
+    self.close_timeout = close_timeout;
+  }
+
+  // Frames from the server that declare a bigger payload are refused with 1009 before any of it
+  // is read
+  pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+    self.max_frame_size = max_frame_size;
+  }
+
+  // Messages from the server that are bigger once decompressed are refused with 1009
+  pub fn set_max_message_size(&mut self, max_message_size: usize) {
+    self.max_message_size = max_message_size;
+  }
+
+  // How many messages from the server are kept until read_message takes them, anything arriving
+  // while that many are waiting is dropped
+  pub fn set_receive_buffer(&mut self, receive_buffer: usize) {
+    self.receive_buffer = receive_buffer.max(1);
+  }
+
+  // Why the connection closed, None while it is still open
+  pub async fn close_reason(&self) -> Option<CloseReason> {
+    self.close_reason.lock().await.clone()
+  }
+
+  // Runs the close handshake, giving up on the server's reply after the close timeout
+  pub async fn close(&mut self, code: u16, reason: &str) {
+    let write_stream = self.write_stream.as_ref().expect("Stream not instantiated");
+    if !self.closing.swap(true, Ordering::SeqCst) {
+      Self::send_close_frame(write_stream, &CloseReason::new(code, reason)).await;
+    }
     if let Some(jh) = self.reader_thread.take() {
-      jh.await.unwrap();
+      let abort_handle = jh.abort_handle();
+      if tokio::time::timeout(self.close_timeout, jh).await.is_err() {
+        warn!("Server did not answer close frame in time");
+        abort_handle.abort();
+        *self.close_reason.lock().await = Some(CloseReason::new(CLOSE_ABNORMAL, ""));
+      }
     }
-    self
-      .write_stream
-      .as_mut()
-      .expect("Stream not instantiated")
-      .lock()
-      .await
-      .shutdown()
-      .await
-      .expect("Shutdown failed");
+    if write_stream.lock().await.shutdown().await.is_err() {
+      debug!("Connection already shut down");
+    }
+    self.connected = false;
     info!("Client disconnected");
   }
+
+  pub async fn disconnect(&mut self) {
+    self.close(CLOSE_NORMAL, "").await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_server_urls() {
+    assert_eq!(
+      parse_uri("ws://localhost:8080/chat"),
+      Some((
+        false,
+        String::from("localhost"),
+        8080,
+        String::from("/chat")
+      ))
+    );
+    assert_eq!(
+      parse_uri("wss://example.com"),
+      Some((true, String::from("example.com"), 443, String::from("/")))
+    );
+    assert_eq!(
+      parse_uri("localhost:9000"),
+      Some((false, String::from("localhost"), 9000, String::from("/")))
+    );
+    assert_eq!(
+      parse_uri("ws://[::1]:8080/a/b"),
+      Some((false, String::from("::1"), 8080, String::from("/a/b")))
+    );
+    assert_eq!(
+      parse_uri("wss://[::1]"),
+      Some((true, String::from("::1"), 443, String::from("/")))
+    );
+  }
+
+  #[test]
+  fn rejects_bad_server_urls() {
+    for bad in [
+      "http://localhost:8080",
+      "ws://:8080",
+      "ws://localhost:port",
+      "ws://[::1]8080",
+      "ws://[::1",
+    ] {
+      assert_eq!(parse_uri(bad), None, "{}", bad);
+    }
+  }
+
+  fn unpack(
+    buf: &mut Vec<u8>,
+    inflater: &mut Option<Inflater>,
+  ) -> Result<Option<(u8, Option<Payload>)>, u16> {
+    unpack_server_frame(buf, 1 << 20, 1 << 20, inflater)
+  }
+
+  fn unmask(frame: &[u8], header_len: usize) -> Vec<u8> {
+    let key = &frame[header_len..header_len + 4];
+    frame[header_len + 4..]
+      .iter()
+      .enumerate()
+      .map(|(i, byte)| byte ^ key[i % 4])
+      .collect()
+  }
+
+  #[test]
+  fn packs_masked_frames() {
+    let key = vec![1, 2, 3, 4];
+    let frame = pack_message_frame(0x1, b"hello", &key);
+    assert_eq!(&frame[..6], &[0x81, 0x80 | 5, 1, 2, 3, 4]);
+    assert_eq!(unmask(&frame, 2), b"hello");
+
+    let payload = vec![9; 300];
+    let frame = pack_message_frame(0x2, &payload, &key);
+    assert_eq!(&frame[..4], &[0x82, 0x80 | 126, 0x01, 0x2c]);
+    assert_eq!(unmask(&frame, 4), payload);
+
+    let payload = vec![9; 70000];
+    let frame = pack_message_frame(0x2, &payload, &key);
+    assert_eq!(&frame[..2], &[0x82, 0x80 | 127]);
+    assert_eq!(&frame[2..10], &70000u64.to_be_bytes());
+    assert_eq!(unmask(&frame, 10), payload);
+  }
+
+  #[test]
+  fn unpacks_text_and_binary_frames() {
+    let mut buf = vec![0x81, 5];
+    buf.extend_from_slice(b"hello");
+    assert!(matches!(
+      unpack(&mut buf, &mut None),
+      Ok(Some((0x1, Some(Payload::Text(text))))) if text == "hello"
+    ));
+
+    let mut buf = vec![0x82, 126, 0x01, 0x2c];
+    buf.extend_from_slice(&[7; 300]);
+    assert!(matches!(
+      unpack(&mut buf, &mut None),
+      Ok(Some((0x2, Some(Payload::Binary(bytes))))) if bytes == [7; 300]
+    ));
+  }
+
+  #[test]
+  fn rejects_masked_frames() {
+    let mut buf = vec![0x81, 0x80 | 2, 0, 0, 0, 0, b'h', b'i'];
+    assert!(matches!(
+      unpack(&mut buf, &mut None),
+      Err(CLOSE_PROTOCOL_ERROR)
+    ));
+  }
+
+  #[test]
+  fn rejects_oversized_control_frames() {
+    let mut buf = vec![0x89, 126, 0, 126];
+    buf.extend_from_slice(&[0; 126]);
+    assert!(matches!(
+      unpack(&mut buf, &mut None),
+      Err(CLOSE_PROTOCOL_ERROR)
+    ));
+  }
+
+  #[test]
+  fn waits_for_the_whole_frame() {
+    let mut buf = vec![0x82, 126, 0x01];
+    assert!(matches!(unpack(&mut buf, &mut None), Ok(None)));
+    buf.extend_from_slice(&[0x2c, 7]);
+    assert!(matches!(unpack(&mut buf, &mut None), Ok(None)));
+    buf.extend_from_slice(&[7; 299]);
+    buf.push(0x8A);
+    assert!(matches!(unpack(&mut buf, &mut None), Ok(Some((0x2, _)))));
+    // the next frame is left in the buffer
+    assert_eq!(buf, [0x8A]);
+  }
+
+  #[test]
+  fn hands_back_invalid_text_without_a_payload() {
+    let mut buf = vec![0x81, 2, 0xc3, 0x28];
+    assert!(matches!(unpack(&mut buf, &mut None), Ok(Some((0x1, None)))));
+  }
+
+  #[test]
+  fn rejects_oversized_frames_from_their_header() {
+    // only the header, the declared payload never has to arrive
+    let mut buf = vec![0x82, 127];
+    buf.extend_from_slice(&(1u64 << 40).to_be_bytes());
+    assert!(matches!(
+      unpack_server_frame(&mut buf, 1000, 1 << 20, &mut None),
+      Err(CLOSE_MESSAGE_TOO_BIG)
+    ));
+    let mut buf = vec![0x82, 126, 0x01, 0x2c];
+    assert!(matches!(
+      unpack_server_frame(&mut buf, 1 << 20, 299, &mut None),
+      Err(CLOSE_MESSAGE_TOO_BIG)
+    ));
+    let mut buf = vec![0x82, 127];
+    buf.extend_from_slice(&(1u64 << 63).to_be_bytes());
+    assert!(matches!(
+      unpack(&mut buf, &mut None),
+      Err(CLOSE_PROTOCOL_ERROR)
+    ));
+  }
+
+  #[test]
+  fn stops_inflating_at_the_message_size() {
+    let params = parse_extensions("permessage-deflate").unwrap();
+    let compressed = Deflater::new(&params).compress(&[0; 10000]);
+    let mut buf = vec![0xC2, compressed.len() as u8];
+    buf.extend_from_slice(&compressed);
+    assert!(matches!(
+      unpack_server_frame(
+        &mut buf,
+        1 << 20,
+        1000,
+        &mut Some(Inflater::new(&params, 1000))
+      ),
+      Err(CLOSE_MESSAGE_TOO_BIG)
+    ));
+  }
+
+  #[test]
+  fn parses_the_servers_deflate_parameters() {
+    let params = parse_extensions(
+      "permessage-deflate; server_no_context_takeover; server_max_window_bits=12; \
+      client_max_window_bits=\"10\"",
+    )
+    .unwrap();
+    assert!(params.server_no_context_takeover);
+    assert!(!params.client_no_context_takeover);
+    assert_eq!(params.client_max_window_bits, 10);
+    for bad in [
+      "x-webkit-deflate-frame",
+      "permessage-deflate; unknown_parameter",
+      "permessage-deflate; client_no_context_takeover=1",
+      "permessage-deflate; server_max_window_bits=16",
+      // zlib can't write with an 8 bit window
+      "permessage-deflate; client_max_window_bits=8",
+    ] {
+      assert!(parse_extensions(bad).is_none(), "{}", bad);
+    }
+  }
+
+  #[test]
+  fn unpacks_compressed_frames() {
+    let params = parse_extensions("permessage-deflate").unwrap();
+    let message = "hello hello hello hello".repeat(10);
+    let mut deflater = Deflater::new(&params);
+    let mut inflater = Some(Inflater::new(&params, 1 << 20));
+    for _ in 0..3 {
+      let compressed = deflater.compress(message.as_bytes());
+      assert!(compressed.len() < 126);
+      let mut buf = vec![0xC1, compressed.len() as u8];
+      buf.extend_from_slice(&compressed);
+      assert!(matches!(
+        unpack(&mut buf, &mut inflater),
+        Ok(Some((0x1, Some(Payload::Text(text))))) if text == message
+      ));
+    }
+  }
+
+  #[test]
+  fn rejects_compressed_frames_without_deflate() {
+    let mut buf = vec![0xC1, 1, 0];
+    assert!(matches!(
+      unpack(&mut buf, &mut None),
+      Err(CLOSE_PROTOCOL_ERROR)
+    ));
+    let params = parse_extensions("permessage-deflate").unwrap();
+    let mut buf = vec![0xC9, 1, 0];
+    assert!(matches!(
+      unpack(&mut buf, &mut Some(Inflater::new(&params, 1 << 20))),
+      Err(CLOSE_PROTOCOL_ERROR)
+    ));
+  }
+
+  #[tokio::test]
+  async fn drops_messages_once_the_receive_buffer_is_full() {
+    let (client, mut server) = tokio::io::duplex(1024);
+    let (mut read_half, write_half) = tokio::io::split(Box::new(client) as Box<dyn ClientStream>);
+    for text in ["one", "two", "three"] {
+      server.write_all(&[0x81, text.len() as u8]).await.unwrap();
+      server.write_all(text.as_bytes()).await.unwrap();
+    }
+    server.write_all(&[0x88, 2, 0x03, 0xE8]).await.unwrap();
+    let (received_tx, mut received_rx) = mpsc::channel(2);
+    let close = ClientSocket::reader_loop(
+      &mut read_half,
+      &Arc::new(Mutex::new(write_half)),
+      received_tx,
+      &AtomicBool::new(false),
+      None,
+      1 << 20,
+      1 << 20,
+    )
+    .await;
+    assert_eq!(close.code, CLOSE_NORMAL);
+    for expected in ["one", "two"] {
+      assert!(matches!(received_rx.recv().await, Some(Payload::Text(text)) if text == expected));
+    }
+    assert!(received_rx.recv().await.is_none());
+  }
+
+  #[test]
+  fn packs_binary_recipients() {
+    assert_eq!(
+      pack_binary_message(&[1, 0x01020304], b"hi").unwrap(),
+      [0, 2, 0, 0, 0, 1, 1, 2, 3, 4, b'h', b'i']
+    );
+    assert_eq!(pack_binary_message(&[], b"").unwrap(), [0, 0]);
+    assert!(pack_binary_message(&vec![1; 1 << 16], b"hi").is_err());
+    assert!(pack_binary_message(&[1 << 32], b"hi").is_err());
+  }
+
+  #[test]
+  fn close_payloads() {
+    let close = CloseReason::new(CLOSE_NORMAL, "bye");
+    let payload = pack_close_payload(&close);
+    assert_eq!(payload, [0x03, 0xE8, b'b', b'y', b'e']);
+    let close = unpack_close_payload(&payload).unwrap();
+    assert_eq!((close.code, close.reason.as_str()), (CLOSE_NORMAL, "bye"));
+    assert!(pack_close_payload(&CloseReason::new(CLOSE_NO_STATUS, "")).is_empty());
+    assert_eq!(unpack_close_payload(&[]).unwrap().code, CLOSE_NO_STATUS);
+    assert_eq!(
+      unpack_close_payload(&[0x03]).unwrap_err(),
+      CLOSE_PROTOCOL_ERROR
+    );
+    assert_eq!(
+      unpack_close_payload(&[0x03, 0xED]).unwrap_err(),
+      CLOSE_PROTOCOL_ERROR
+    );
+    assert_eq!(
+      unpack_close_payload(&[0x03, 0xE8, 0xff]).unwrap_err(),
+      CLOSE_INVALID_DATA
+    );
+  }
 }
@@ -4,8 +4,7 @@ mod utils;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use std::env::set_var;
-use tracing::info;
-use utils::Opts;
+use utils::{sign_token, Opts, DEFAULT_TOKEN_TTL};
 
 pub async fn run(opts: Opts) {
   let i = *opts.my_id();
@@ -15,7 +14,28 @@ pub async fn run(opts: Opts) {
   let sleep_mean: u32 = *opts.sleep_time_mean();
   let message_length: usize = *opts.message_length() as usize;
   let sleep_padding: u32 = 2000;
-  let mut my_client = testclient::TestClient::new(String::from("localhost:8080"), i);
+  let token = match opts.token() {
+    Some(token) => token.clone(),
+    // clap makes sure there is a key when no token was given
+    None => sign_token(opts.key().as_ref().unwrap(), i, DEFAULT_TOKEN_TTL),
+  };
+  let mut my_client = testclient::TestClient::new(opts.url().clone(), i, token);
+  let socket = my_client.socket_mut();
+  if let Some(ca_file) = opts.ca_file() {
+    socket.add_root_certificates(ca_file);
+  }
+  if let Some(server_name) = opts.server_name() {
+    socket.set_server_name(server_name.clone());
+  }
+  socket.set_insecure(*opts.insecure());
+  socket.set_compression(!*opts.no_deflate());
+  socket.set_protocols(opts.protocols().clone());
+  if let Some(max_frame_size) = opts.max_frame_size() {
+    socket.set_max_frame_size(*max_frame_size);
+  }
+  if let Some(max_message_size) = opts.max_message_size() {
+    socket.set_max_message_size(*max_message_size);
+  }
   let rng = thread_rng();
   let random_msg: String = rng
     .sample_iter(&Alphanumeric)
@@ -38,7 +58,6 @@ pub async fn run(opts: Opts) {
 pub fn main() {
   set_var("RUST_BACKTRACE", "1");
   let opts: Opts = Opts::new();
-  info!("Running with options: {:?}", opts);
   tokio::runtime::Builder::new_multi_thread()
     .enable_all()
     .build()
@@ -6,12 +6,17 @@ use tracing::info;
 pub struct TestClient {
   socket: ClientSocket,
   id: u32,
+  token: String,
 }
 
 impl TestClient {
-  pub fn new(uri: String, id: u32) -> TestClient {
+  pub fn new(uri: String, id: u32, token: String) -> TestClient {
     let socket: ClientSocket = ClientSocket::new(uri);
-    TestClient { id, socket }
+    TestClient { id, token, socket }
+  }
+
+  pub fn socket_mut(&mut self) -> &mut ClientSocket {
+    &mut self.socket
   }
 
   pub async fn run_client(
@@ -24,7 +29,10 @@ impl TestClient {
     sleep_padding: u64,
   ) -> std::io::Result<()> {
     let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_entropy();
-    self.socket.connect(self.id).await;
+    self.socket.connect(&self.token).await;
+    if let Some(protocol) = self.socket.protocol() {
+      info!("Client socket {} speaks {}", self.id, protocol);
+    }
     tokio::time::sleep(std::time::Duration::from_millis(sleep_padding)).await;
     for _ in 0..repeats {
       let recipients: Vec<usize> = sample(&mut rng, num_clients, out_degree).into_vec();
@@ -37,6 +45,9 @@ impl TestClient {
     }
     tokio::time::sleep(std::time::Duration::from_millis(sleep_padding)).await;
     self.socket.disconnect().await;
+    if let Some(close) = self.socket.close_reason().await {
+      info!("Client socket {} closed: {}", self.id, close);
+    }
     Ok(())
   }
 }
@@ -1,10 +1,27 @@
 use base64::engine::general_purpose;
 use base64::Engine;
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
 use getset::Getters;
+use hmac::{Hmac, Mac};
 use sha1::Digest;
+use sha2::Sha256;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub const WEBSOCKET_PREFIX: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+pub const DEFAULT_CLOSE_TIMEOUT: u64 = 5000;
+pub const DEFAULT_URL: &str = "ws://localhost:8080";
+pub const DEFAULT_TOKEN_TTL: u64 = 60 * 60;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+pub const DEFAULT_RECEIVE_BUFFER: usize = 256;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_NO_STATUS: u16 = 1005;
+pub const CLOSE_ABNORMAL: u16 = 1006;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
 
 pub fn sec_websocket_key(client_key: String) -> String {
   let combined = client_key + WEBSOCKET_PREFIX;
@@ -15,7 +32,26 @@ pub fn sec_websocket_key(client_key: String) -> String {
   my_key
 }
 
-#[derive(Debug, Getters)]
+// Registration token for the server: "<id>.<expiry as unix seconds>.<signature>", the signature
+// being an HMAC-SHA256 of the first two parts under the server key, base64url without padding
+pub fn sign_token(key: &str, id: u32, ttl_secs: u64) -> String {
+  let expires = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Clock is before 1970")
+    .as_secs()
+    + ttl_secs;
+  let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+  mac.update(format!("{}.{}", id, expires).as_bytes());
+  let signature = mac.finalize().into_bytes();
+  format!(
+    "{}.{}.{}",
+    id,
+    expires,
+    general_purpose::URL_SAFE_NO_PAD.encode(signature)
+  )
+}
+
+#[derive(Getters)]
 pub struct Opts {
   #[getset(get = "pub")]
   my_id: u32,
@@ -29,6 +65,26 @@ pub struct Opts {
   sleep_time_mean: u32,
   #[getset(get = "pub")]
   message_length: u32,
+  #[getset(get = "pub")]
+  url: String,
+  #[getset(get = "pub")]
+  ca_file: Option<PathBuf>,
+  #[getset(get = "pub")]
+  server_name: Option<String>,
+  #[getset(get = "pub")]
+  insecure: bool,
+  #[getset(get = "pub")]
+  no_deflate: bool,
+  #[getset(get = "pub")]
+  protocols: Vec<String>,
+  #[getset(get = "pub")]
+  key: Option<String>,
+  #[getset(get = "pub")]
+  token: Option<String>,
+  #[getset(get = "pub")]
+  max_frame_size: Option<usize>,
+  #[getset(get = "pub")]
+  max_message_size: Option<usize>,
 }
 
 impl Opts {
@@ -95,6 +151,90 @@ impl Opts {
           .required(false)
           .default_value("10")
           .num_args(1),
+      )
+      .arg(
+        Arg::new("url")
+          .short('u')
+          .long("url")
+          .value_name("URL")
+          .help("sets the server to connect to, ws:// or wss://")
+          .required(false)
+          .default_value(DEFAULT_URL)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("ca_file")
+          .long("ca_file")
+          .value_name("FILE")
+          .help("trusts the CA certificates in this PEM file for wss")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("server_name")
+          .long("server_name")
+          .value_name("NAME")
+          .help("sets the TLS server name to use instead of the host in the URL")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("insecure")
+          .long("insecure")
+          .help("skips checking the server certificate, for testing only")
+          .required(false)
+          .action(ArgAction::SetTrue),
+      )
+      .arg(
+        Arg::new("no_deflate")
+          .long("no_deflate")
+          .help("does not offer permessage-deflate compression")
+          .required(false)
+          .action(ArgAction::SetTrue),
+      )
+      .arg(
+        Arg::new("protocol")
+          .long("protocol")
+          .value_name("NAME")
+          .help("offers a subprotocol, repeat or comma separate for several in order of preference")
+          .required(false)
+          .action(ArgAction::Append)
+          .value_delimiter(','),
+      )
+      .arg(
+        Arg::new("key")
+          .short('k')
+          .long("key")
+          .env("SOCKET_SERVER_KEY")
+          .value_name("KEY")
+          .help("sets the server key to sign the registration token with")
+          .required_unless_present("token")
+          .hide_env_values(true)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("token")
+          .long("token")
+          .value_name("TOKEN")
+          .help("registers with a token from the server's --issue_token instead of signing one")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("max_frame_size")
+          .long("max_frame_size")
+          .value_name("BYTES")
+          .help("sets the maximum payload size of a frame from the server")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("max_message_size")
+          .long("max_message_size")
+          .value_name("BYTES")
+          .help("sets the maximum size of a message from the server once decompressed")
+          .required(false)
+          .num_args(1),
       );
 
     let matches = app.get_matches();
@@ -110,6 +250,22 @@ impl Opts {
     let sleep_time_mean: u32 = sleep_time_str.parse::<u32>().unwrap();
     let message_length_str: &String = matches.get_one("message_length").unwrap();
     let message_length: u32 = message_length_str.parse::<u32>().unwrap();
+    let url: String = matches.get_one::<String>("url").unwrap().clone();
+    let ca_file: Option<PathBuf> = matches.get_one::<String>("ca_file").map(PathBuf::from);
+    let server_name: Option<String> = matches.get_one::<String>("server_name").cloned();
+    let insecure: bool = matches.get_flag("insecure");
+    let no_deflate: bool = matches.get_flag("no_deflate");
+    let protocols: Vec<String> = matches
+      .get_many::<String>("protocol")
+      .map_or_else(Vec::new, |protocols| protocols.cloned().collect());
+    let key: Option<String> = matches.get_one::<String>("key").cloned();
+    let token: Option<String> = matches.get_one::<String>("token").cloned();
+    let max_frame_size: Option<usize> = matches
+      .get_one::<String>("max_frame_size")
+      .map(|size| size.parse::<usize>().unwrap());
+    let max_message_size: Option<usize> = matches
+      .get_one::<String>("max_message_size")
+      .map(|size| size.parse::<usize>().unwrap());
     let opts = Opts {
       my_id,
       repeats,
@@ -117,6 +273,16 @@ impl Opts {
       out_degree,
       sleep_time_mean,
       message_length,
+      url,
+      ca_file,
+      server_name,
+      insecure,
+      no_deflate,
+      protocols,
+      key,
+      token,
+      max_frame_size,
+      max_message_size,
     };
     opts
   }
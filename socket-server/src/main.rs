@@ -1,24 +1,56 @@
 use std::env::set_var;
+use std::fs::OpenOptions;
 mod run;
 mod server;
 mod utils;
+use crate::server::auth::sign_token;
+use crate::utils::config::ServerConfig;
 use crate::utils::utils::Opts;
 use run::run::run;
-use tracing::{info, Level};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
 
 fn main() {
   set_var("RUST_BACKTRACE", "1");
-  tracing_subscriber::fmt()
-    .with_max_level(Level::TRACE)
-    .init();
   let opts: Opts = Opts::new();
-  info!("Starting server with options: {:?}", opts);
+  let config: ServerConfig = ServerConfig::load(&opts).unwrap_or_else(|err| {
+    eprintln!("{}", err);
+    std::process::exit(1);
+  });
+  if let Some(id) = opts.issue_token() {
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .expect("Clock is before 1970")
+      .as_secs();
+    let expires = now + config.auth().token_ttl_secs();
+    println!("{}", sign_token(config.auth().key(), *id, expires));
+    return;
+  }
+  let subscriber = tracing_subscriber::fmt().with_max_level(config.log().max_level());
+  match config.log().file() {
+    Some(path) => {
+      let log_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .expect("Failed to open log file");
+      subscriber
+        .with_ansi(false)
+        .with_writer(move || log_file.try_clone().expect("Failed to write log file"))
+        .init();
+    }
+    None => subscriber.init(),
+  }
+  info!("Starting server with config: {:?}", config);
+  if config.auth().uses_default_key() {
+    warn!("Running with the public default server key, anyone can register as any client");
+  }
   tokio::runtime::Builder::new_multi_thread()
-    .worker_threads(*opts.threads())
+    .worker_threads(*config.threads())
     .enable_all()
     .build()
     .unwrap()
     .block_on(async {
-      run().await;
+      run(config).await;
     })
 }
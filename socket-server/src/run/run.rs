@@ -1,7 +1,17 @@
 use crate::server::concurrent::ConcurrentServer;
+use crate::utils::config::ServerConfig;
+use tracing::info;
 
-pub async fn run() {
-  let mut my_server =
-    ConcurrentServer::new(String::from("::1"), 8080, "1234567890".to_string()).await;
+pub async fn run(config: ServerConfig) {
+  let protocols = config.protocols().clone();
+  let mut my_server = ConcurrentServer::new(config)
+    .await
+    .expect("Failed to bind server");
+  for protocol in &protocols {
+    my_server.register_protocol(protocol);
+  }
+  for address in my_server.local_addrs().unwrap() {
+    info!("Listening on {}", address);
+  }
   my_server.run_server().await.unwrap();
 }
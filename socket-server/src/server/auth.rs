@@ -0,0 +1,132 @@
+use base64::engine::general_purpose;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug)]
+pub enum AuthError {
+  Missing,
+  Malformed,
+  Expired,
+  BadSignature,
+}
+
+impl fmt::Display for AuthError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      AuthError::Missing => write!(f, "no token"),
+      AuthError::Malformed => write!(f, "malformed token"),
+      AuthError::Expired => write!(f, "token expired"),
+      AuthError::BadSignature => write!(f, "bad token signature"),
+    }
+  }
+}
+
+fn mac(key: &str, id: u32, expires: u64) -> HmacSha256 {
+  // HMAC takes keys of any length, so this cannot fail
+  let mut mac = HmacSha256::new_from_slice(key.as_bytes()).unwrap();
+  mac.update(format!("{}.{}", id, expires).as_bytes());
+  mac
+}
+
+// Tokens look like "<id>.<expiry as unix seconds>.<signature>", the signature being an
+// HMAC-SHA256 of the first two parts under the server key, base64url without padding
+pub fn sign_token(key: &str, id: u32, expires: u64) -> String {
+  let signature = mac(key, id, expires).finalize().into_bytes();
+  format!(
+    "{}.{}.{}",
+    id,
+    expires,
+    general_purpose::URL_SAFE_NO_PAD.encode(signature)
+  )
+}
+
+// Checks a registration token and returns the client id it was issued for
+pub fn verify_token(key: &str, token: &str) -> Result<u32, AuthError> {
+  let mut parts = token.trim().split('.');
+  let (id, expires, signature) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+    (Some(id), Some(expires), Some(signature), None) => (id, expires, signature),
+    _ => return Err(AuthError::Malformed),
+  };
+  let id = id.parse::<u32>().map_err(|_| AuthError::Malformed)?;
+  let expires = expires.parse::<u64>().map_err(|_| AuthError::Malformed)?;
+  let signature = general_purpose::URL_SAFE_NO_PAD
+    .decode(signature)
+    .map_err(|_| AuthError::Malformed)?;
+  // compared in constant time so the signature can't be guessed byte by byte
+  mac(key, id, expires)
+    .verify_slice(&signature)
+    .map_err(|_| AuthError::BadSignature)?;
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |now| now.as_secs());
+  if expires < now {
+    return Err(AuthError::Expired);
+  }
+  Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const KEY: &str = "test key";
+  // far enough ahead to never expire while the tests run
+  const LATER: u64 = 4_000_000_000;
+
+  #[test]
+  fn accepts_a_signed_token() {
+    let token = sign_token(KEY, 42, LATER);
+    assert!(token.starts_with("42.4000000000."));
+    assert_eq!(verify_token(KEY, &token).unwrap(), 42);
+    assert_eq!(verify_token(KEY, &format!(" {}\n", token)).unwrap(), 42);
+  }
+
+  #[test]
+  fn rejects_a_token_signed_with_another_key() {
+    let token = sign_token("another key", 42, LATER);
+    assert!(matches!(
+      verify_token(KEY, &token),
+      Err(AuthError::BadSignature)
+    ));
+  }
+
+  #[test]
+  fn rejects_a_token_for_another_id() {
+    let token = sign_token(KEY, 42, LATER).replacen("42", "43", 1);
+    assert!(matches!(
+      verify_token(KEY, &token),
+      Err(AuthError::BadSignature)
+    ));
+  }
+
+  #[test]
+  fn rejects_an_expired_token() {
+    let token = sign_token(KEY, 42, 1);
+    assert!(matches!(verify_token(KEY, &token), Err(AuthError::Expired)));
+  }
+
+  #[test]
+  fn rejects_malformed_tokens() {
+    let signature = sign_token(KEY, 42, LATER);
+    let signature = signature.rsplit('.').next().unwrap();
+    for token in [
+      String::new(),
+      String::from("42.4000000000"),
+      format!("42.4000000000.{}.extra", signature),
+      format!("x.4000000000.{}", signature),
+      format!("42.soon.{}", signature),
+      String::from("42.4000000000.not base64!"),
+    ] {
+      assert!(
+        matches!(verify_token(KEY, &token), Err(AuthError::Malformed)),
+        "{:?}",
+        token
+      );
+    }
+  }
+}
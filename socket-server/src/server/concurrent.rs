@@ -1,349 +1,1116 @@
-use crate::server::connectedclient::ConnectedClient;
+use crate::server::auth::{verify_token, AuthError};
+use crate::server::connectedclient::{ClientReader, ClientStream, ClientWriter, ConnectedClient};
+use crate::server::deflate::{negotiate, DeflateParams, Deflater, Inflater};
+use crate::server::frame::{
+  pack_close_frame, pack_frame, unpack_close_payload, CloseReason, FrameDecoder, FrameError,
+  MessageAssembler, Payload, CLOSE_GOING_AWAY, CLOSE_POLICY_VIOLATION,
+};
+use crate::server::handshake::{
+  accept_key, query_param, read_request, select_protocol, switching_protocols_response,
+  HandshakeError, REQUEST_TIMEOUT,
+};
+use crate::server::message::{
+  parse_envelope, parse_legacy, reply, MessageFormat, Outgoing, Request, JSON_PROTOCOL,
+};
+use crate::server::outbound::{Outbound, OutboundStats, Push};
+use crate::server::ratelimit::{ConnectionLimiter, RateLimitStats, Verdict};
+use crate::server::rooms::Presence;
+use crate::server::routing::{resolve, ClientMap, Delivery, Registry, Route, SessionKey};
+use crate::server::tls::{create_acceptor, CertificateResolver};
+use crate::utils::config::{DuplicateIdPolicy, ServerConfig};
 use crate::utils::logging::*;
-use crate::utils::utils::sec_websocket_key;
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::vec;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
 use tracing::{debug, error, info, warn};
 
-type ClientMap = Arc<RwLock<HashMap<u32, Mutex<ConnectedClient>>>>;
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(0);
 
-async fn create_listener(ip: String, port: u16) -> TcpListener {
-  let address: SocketAddr = format!("[{}]:{}", ip, port).parse().unwrap();
-  let listener: TcpListener = TcpListener::bind(address).await.unwrap();
-  listener
+async fn create_listener(ip: IpAddr, port: u16) -> std::io::Result<TcpListener> {
+  TcpListener::bind(SocketAddr::new(ip, port)).await
 }
 
-fn pack_message_frame(payload: String) -> Vec<u8> {
-  // FIN = 1 (only frame in message), RSV1-3 = 0, opcode = 0001 (text frame)
-  let mut frame: Vec<u8> = vec![0b10000001];
-  frame.reserve(1024);
-
-  let mut second_byte: u8 = 0;
-  let strlen = payload.len() as u64;
-  let mut len_bytes = 0;
-  //if from_client { // set mask bit
-  //second_byte += 1 << 7;
-  //}
-  if strlen > 65535 {
-    // 8 byte payload len
-    second_byte += 127;
-    len_bytes = 8;
-  } else if strlen > 125 {
-    // 2 byte payload len
-    second_byte += 126;
-    len_bytes = 2;
-  } else {
-    second_byte += (strlen) as u8;
-  }
-  frame.push(second_byte);
-
-  if len_bytes == 8 {
-    let bytes = u64::to_be_bytes(strlen);
-    frame.extend_from_slice(&bytes);
-  } else if len_bytes == 2 {
-    let bytes = u16::to_be_bytes(strlen as u16);
-    frame.extend_from_slice(&bytes);
-  }
-
-  frame.extend_from_slice(payload.as_bytes());
-  frame
-}
-
-fn unpack_client_frame(buf: &mut Vec<u8>) -> (Option<u8>, Option<String>) {
-  let first_byte = buf[0];
-  let fin: bool = (first_byte & 128) >> 7 == 1;
-  if !fin {
-    // change
-    return (None, None);
+// Binary messages carry their recipients up front: a big-endian u16 count followed by that many
+// big-endian u32 client ids, with the rest of the frame being the raw message
+fn unpack_binary_message(data: &[u8]) -> Option<(Vec<u32>, Vec<u8>)> {
+  if data.len() < 2 {
+    return None;
   }
-  let rsv: u8 = first_byte & 0b01110000;
-  if rsv != 0 {
-    return (None, None);
+  let num_ids = u16::from_be_bytes([data[0], data[1]]) as usize;
+  let body_start = 2 + num_ids * 4;
+  if data.len() < body_start {
+    return None;
   }
-  let opcode: u8 = first_byte & 15;
-  if opcode != 1 {
-    // text frame, change this later
-    return (Some(opcode), None);
-  }
-
-  let second_byte = buf[1];
-  let mask: bool = (second_byte & 128) >> 7 == 1;
-  if !mask {
-    // clients must mask stuff
-    return (None, None);
-  }
-  let second_byte_payload_len = second_byte & 127;
-  let mut payload_len: usize = second_byte_payload_len as usize;
-  let mut payload_len_bytes: usize = 0;
-  if second_byte_payload_len == 127 {
-    payload_len_bytes = 8;
-    payload_len = u64::from_be_bytes(buf[2..10].try_into().unwrap()) as usize;
-  } else if second_byte_payload_len == 126 {
-    payload_len_bytes = 2;
-    payload_len = u16::from_be_bytes(buf[2..4].try_into().unwrap()) as usize;
-  }
-
-  let mask_key_start = payload_len_bytes + 2;
-  let mut masking_key: Vec<u8> = vec![0; 4];
-  masking_key.clone_from_slice(&buf[mask_key_start..mask_key_start + 4]);
-
-  let payload = &mut buf[mask_key_start + 4..mask_key_start + 4 + payload_len];
-  for i in 0..payload_len {
-    payload[i] ^= masking_key[i % 4];
-  }
-  let s = (*String::from_utf8_lossy(payload)).to_string();
+  let ids: Vec<u32> = data[2..body_start]
+    .chunks_exact(4)
+    .map(|id| u32::from_be_bytes(id.try_into().unwrap()))
+    .collect();
+  Some((ids, data[body_start..].to_vec()))
+}
 
-  (Some(opcode), Some(s))
+// What the opening handshake settled on
+struct Upgrade {
+  // bytes the client sent right after its request
+  leftover: Vec<u8>,
+  // registration token from the query string, otherwise it comes in the first message
+  token: Option<String>,
+  protocol: Option<String>,
+  format: MessageFormat,
+  deflate: Option<DeflateParams>,
 }
 
-#[derive(Debug)]
 pub struct ConcurrentServer {
-  key: String,
-  listener: TcpListener,
+  config: Arc<ServerConfig>,
+  listeners: Vec<Arc<TcpListener>>,
   server_log: Arc<Mutex<Logger>>,
-  clients: ClientMap,
+  registry: Registry,
+  // open connections, counting ones still in the handshake
+  connections: Arc<AtomicUsize>,
+  tls_acceptor: Option<TlsAcceptor>,
+  cert_resolver: Option<Arc<CertificateResolver>>,
+  // subprotocols clients can pick from, in no particular order
+  protocols: Vec<String>,
+  connection_limiter: ConnectionLimiter,
 }
 
 impl ConcurrentServer {
-  pub async fn new(ip: String, port: u16, key: String) -> ConcurrentServer {
-    info!("Starting server on {}:{}", ip, port);
-    ConcurrentServer {
-      key,
-      listener: create_listener(ip, port).await,
+  pub async fn new(config: ServerConfig) -> std::io::Result<ConcurrentServer> {
+    info!(
+      "Starting server on {:?} port {}",
+      config.addresses(),
+      config.port()
+    );
+    let mut listeners: Vec<Arc<TcpListener>> = Vec::with_capacity(config.addresses().len());
+    for ip in config.addresses() {
+      listeners.push(Arc::new(create_listener(*ip, *config.port()).await?));
+    }
+    let (tls_acceptor, cert_resolver) = match create_acceptor(config.tls())
+      .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?
+    {
+      Some((acceptor, resolver)) => (Some(acceptor), Some(resolver)),
+      None => (None, None),
+    };
+    let registry = Registry::new(&config);
+    let connection_limiter = ConnectionLimiter::new(config.rate_limit());
+    Ok(ConcurrentServer {
+      config: Arc::new(config),
+      listeners,
       server_log: Arc::new(Mutex::new(Logger::new())),
-      clients: ClientMap::new(RwLock::new(HashMap::new())),
+      registry,
+      connections: Arc::new(AtomicUsize::new(0)),
+      tls_acceptor,
+      cert_resolver,
+      // always on offer so clients can switch to JSON envelopes
+      protocols: vec![String::from(JSON_PROTOCOL)],
+      connection_limiter,
+    })
+  }
+
+  // Lets clients ask for this subprotocol in Sec-WebSocket-Protocol. Has to happen before
+  // run_server, and the name has to be a valid HTTP token.
+  pub fn register_protocol(&mut self, protocol: &str) {
+    if !self
+      .protocols
+      .iter()
+      .any(|registered| registered == protocol)
+    {
+      self.protocols.push(String::from(protocol));
     }
   }
 
+  // the addresses actually bound, which differ from the requested ones when the port is 0
+  pub fn local_addrs(&self) -> std::io::Result<Vec<SocketAddr>> {
+    self
+      .listeners
+      .iter()
+      .map(|listener| listener.local_addr())
+      .collect()
+  }
+
   pub async fn run_server(&mut self) -> std::io::Result<()> {
-    let server_log = &mut self.server_log;
+    let cert_reload_interval = self.config.tls().cert_reload_interval();
+    if let Some(resolver) = &self.cert_resolver {
+      if !cert_reload_interval.is_zero() {
+        tokio::spawn(Arc::clone(resolver).watch(cert_reload_interval));
+      }
+    }
+    // every listener feeds the same accept loop
+    let protocols = Arc::new(self.protocols.clone());
+    let (accepted_tx, mut accepted_rx) = mpsc::unbounded_channel();
+    for listener in &self.listeners {
+      let listener = Arc::clone(listener);
+      let accepted_tx = accepted_tx.clone();
+      tokio::spawn(async move { while accepted_tx.send(listener.accept().await).is_ok() {} });
+    }
+    // a zero interval turns the stats line off
+    let stats_interval = self.config.log().stats_interval();
+    let stats_enabled = !stats_interval.is_zero();
+    let stats_period = stats_interval.max(Duration::from_millis(1));
+    let mut stats_timer = tokio::time::interval_at(Instant::now() + stats_period, stats_period);
     loop {
-      let log_copy = Arc::clone(server_log);
-      let clients_copy = Arc::clone(&self.clients);
+      let log_copy = Arc::clone(&self.server_log);
+      let registry_copy = self.registry.clone();
+      let config_copy = Arc::clone(&self.config);
+      let connections_copy = Arc::clone(&self.connections);
+      let tls_copy = self.tls_acceptor.clone();
+      let protocols_copy = Arc::clone(&protocols);
 
-      let (stream, addr) = self.listener.accept().await?;
+      let (stream, addr) = tokio::select! {
+        Some(accepted) = accepted_rx.recv() => accepted?,
+        _ = stats_timer.tick(), if stats_enabled => {
+          self.log_stats().await;
+          continue;
+        }
+        _ = tokio::signal::ctrl_c() => {
+          info!("Shutting down server");
+          self
+            .close_all_clients(CloseReason::new(CLOSE_GOING_AWAY, "Server shutting down"))
+            .await;
+          self.wait_for_clients(self.config.limits().close_timeout()).await;
+          return Ok(());
+        }
+      };
+      if !self.connection_limiter.allow(addr.ip()) {
+        warn!("Turning away {}, too many connection attempts", addr);
+        tokio::spawn(Self::reject_connection(
+          stream,
+          HandshakeError::TooManyRequests,
+        ));
+        continue;
+      }
+      let max_connections = *self.config.limits().max_connections();
+      let open_connections = self.connections.fetch_add(1, Ordering::SeqCst);
+      if max_connections > 0 && open_connections >= max_connections {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+        warn!(
+          "Turning away {}, already serving {} connections",
+          addr, open_connections
+        );
+        tokio::spawn(Self::reject_connection(
+          stream,
+          HandshakeError::ServiceUnavailable,
+        ));
+        continue;
+      }
       info!("New client: {}", addr);
       tokio::spawn(async move {
-        Self::handle_client(&log_copy, stream, clients_copy).await;
+        Self::handle_client(
+          &log_copy,
+          stream,
+          tls_copy,
+          registry_copy,
+          config_copy,
+          protocols_copy,
+        )
+        .await;
+        connections_copy.fetch_sub(1, Ordering::SeqCst);
       });
     }
   }
 
-  async fn verify_client_handshake(stream: &mut TcpStream) -> bool {
-    let mut buf = [0; 1024];
-    let size = stream.read(&mut buf).await.unwrap();
-    let request = String::from_utf8_lossy(&buf[..size]);
-    let lines: std::vec::Vec<&str> = request.split('\n').collect();
-    let first_line: vec::Vec<&str> = lines[0].split(' ').collect();
-    let last_word = format!(r"{}", first_line[2]);
-    if first_line.len() != 3
-      || first_line[0] != "GET"
-      || !first_line[1].starts_with('/')
-      || last_word.trim() != r"HTTP/1.1"
-    {
-      return false;
+  // Starts the close handshake with every session of a client, false if none are connected
+  pub async fn close_client(&self, id: u32, close: CloseReason) -> bool {
+    let client_map = self.registry.clients.read().await;
+    let mut closed = false;
+    if let Some(sessions) = client_map.get(&id) {
+      for client_object_lock in sessions.values() {
+        closed |= client_object_lock.lock().await.request_close(close.clone());
+      }
     }
-    let mut m: HashMap<String, String> = HashMap::new();
-    for line in lines[1..].iter() {
-      let split_line: Vec<&str> = (line.to_owned()).split(": ").collect();
-      if split_line.len() == 2 {
-        m.insert(String::from(split_line[0]), String::from(split_line[1]));
+    closed
+  }
+
+  pub async fn close_all_clients(&self, close: CloseReason) {
+    let ids: Vec<u32> = self.registry.clients.read().await.keys().copied().collect();
+    for id in ids {
+      self.close_client(id, close.clone()).await;
+    }
+  }
+
+  // How far behind each connected session is, one entry per session
+  pub async fn outbound_stats(&self) -> Vec<(u32, OutboundStats)> {
+    let client_map = self.registry.clients.read().await;
+    let mut stats = Vec::new();
+    for (id, sessions) in client_map.iter() {
+      for client_object_lock in sessions.values() {
+        stats.push((*id, client_object_lock.lock().await.outbound_stats()));
       }
     }
-    // let host = m.get("Host").unwrap().to_owned();
-    let upgrade = m.get("Upgrade").unwrap().to_owned();
-    let connection = m.get("Connection").unwrap().to_owned();
-    let key = String::from(m.get("Sec-WebSocket-Key").unwrap().to_owned().trim());
-    //println!("{}", key);
-    let version = m.get("Sec-WebSocket-Version").unwrap().to_owned();
-    // let origin = m.get("Origin").unwrap().to_owned();
-
-    if upgrade.trim() != "websocket" || connection.trim() != "Upgrade" || version.trim() != "13" {
-      return false;
-    }
-
-    let my_key = sec_websocket_key(key);
-    let response: String = format!(
-      "HTTP/1.1 101 Switching Protocols\r\n\
-      Upgrade: websocket\r\n\
-      Connection: Upgrade\r\n\
-      Sec-WebSocket-Accept: {}\r\n\r\n",
-      my_key
+    stats
+  }
+
+  // How often clients went over their rates and connections were refused, since the server started
+  pub async fn rate_limit_stats(&self) -> RateLimitStats {
+    RateLimitStats {
+      refused: self.connection_limiter.refused(),
+      ..self.registry.rates.lock().await.stats()
+    }
+  }
+
+  // Rooms with at least one member
+  pub async fn rooms(&self) -> Vec<String> {
+    self.registry.rooms.read().await.names()
+  }
+
+  // The subprotocol each session of a client agreed on, empty if the client isn't connected
+  pub async fn client_protocols(&self, id: u32) -> Vec<Option<String>> {
+    let client_map = self.registry.clients.read().await;
+    let mut protocols = Vec::new();
+    if let Some(sessions) = client_map.get(&id) {
+      for client_object_lock in sessions.values() {
+        protocols.push(client_object_lock.lock().await.protocol().clone());
+      }
+    }
+    protocols
+  }
+
+  // Ids of the clients in a room, in ascending order, empty if nobody is in it
+  pub async fn room_members(&self, room: &str) -> Vec<u32> {
+    self.registry.rooms.read().await.members(room)
+  }
+
+  // One line with what the stats APIs above report, summed over every session
+  async fn log_stats(&self) {
+    let sessions = self.outbound_stats().await;
+    let (mut queued, mut peak, mut dropped) = (0, 0, 0);
+    for (_, stats) in &sessions {
+      queued += stats.depth;
+      peak = peak.max(stats.peak);
+      dropped += stats.dropped;
+    }
+    let mut protocols: BTreeMap<String, usize> = BTreeMap::new();
+    let ids: BTreeSet<u32> = sessions.iter().map(|(id, _)| *id).collect();
+    for id in ids {
+      for protocol in self.client_protocols(id).await {
+        *protocols
+          .entry(protocol.unwrap_or_else(|| String::from("none")))
+          .or_default() += 1;
+      }
+    }
+    let protocols: Vec<String> = protocols
+      .iter()
+      .map(|(protocol, count)| format!("{} {}", count, protocol))
+      .collect();
+    let rooms = self.rooms().await;
+    let mut members = 0;
+    for room in &rooms {
+      members += self.room_members(room).await.len();
+    }
+    let rates = self.rate_limit_stats().await;
+    info!(
+      "Stats: {} sessions (protocols: {}), {} rooms with {} members, {} messages queued ({} at most \
+       on one session, {} dropped), {} messages over the rate ({} dropped, {} delayed, {} closed), \
+       {} connections refused",
+      sessions.len(),
+      protocols.join(", "),
+      rooms.len(),
+      members,
+      queued,
+      peak,
+      dropped,
+      rates.limited,
+      rates.dropped,
+      rates.delayed,
+      rates.closed,
+      rates.refused
     );
-    stream.write(response.as_bytes()).await.unwrap();
-    true
   }
 
-  pub async fn read_message(
-    server_log: &Arc<Mutex<Logger>>,
-    buf: &mut Vec<u8>,
-    stream: &mut OwnedReadHalf,
-  ) -> (Option<u8>, Option<String>) {
-    match stream.read(buf).await {
-      Ok(size) => {
-        if size == 0 {
-          debug!("size is 0");
-          return (None, None);
-        }
+  async fn wait_for_clients(&self, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while !self.registry.clients.read().await.is_empty() && Instant::now() < deadline {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  }
 
-        let (opcode, payload) = unpack_client_frame(buf);
-        match opcode {
-          None => {
-            return (None, None);
-          }
-          Some(opcode_val) => {
-            if opcode_val == 0x1 {
-              match payload {
-                None => {
-                  return (opcode, payload);
-                }
-                Some(msg) => {
-                  let log_msg: String = format!("Server Read: {}", &msg);
-                  let m: Message = Message::new(log_msg.clone(), ErrorLevel::INFO);
-                  let mut logger = server_log.lock().await;
-                  logger.log(m);
-                  return (opcode, Some(msg));
-                }
-              }
-            } else {
-              return (opcode, payload);
-            }
+  // Runs the TLS handshake when the server has a certificate, otherwise passes the socket through
+  async fn accept_stream(
+    stream: TcpStream,
+    tls_acceptor: Option<TlsAcceptor>,
+  ) -> Option<Box<dyn ClientStream>> {
+    let acceptor = match tls_acceptor {
+      Some(acceptor) => acceptor,
+      None => return Some(Box::new(stream)),
+    };
+    match tokio::time::timeout(REQUEST_TIMEOUT, acceptor.accept(stream)).await {
+      Ok(Ok(tls_stream)) => Some(Box::new(tls_stream)),
+      Ok(Err(err)) => {
+        warn!("TLS handshake failed: {}", err);
+        None
+      }
+      Err(_) => {
+        warn!("TLS handshake timed out");
+        None
+      }
+    }
+  }
+
+  // Runs the opening handshake and picks the subprotocol and compression settings to use
+  async fn verify_client_handshake(
+    stream: &mut Box<dyn ClientStream>,
+    config: &ServerConfig,
+    protocols: &[String],
+  ) -> Option<Upgrade> {
+    let result = match read_request(stream).await {
+      Ok((request, leftover)) => {
+        // the query string can carry the token, which shouldn't end up in the logs
+        let (path, _) = request
+          .path
+          .split_once('?')
+          .unwrap_or((request.path.as_str(), ""));
+        debug!("Client requested {}", path);
+        let token = query_param(&request.path, "token").map(String::from);
+        let protocol = select_protocol(&request, protocols);
+        // a format in the query string wins over the subprotocol
+        let format = match query_param(&request.path, "format") {
+          Some(name) => MessageFormat::from_name(name)
+            .ok_or(HandshakeError::BadRequest("unknown message format")),
+          None if protocol.as_deref() == Some(JSON_PROTOCOL) => Ok(MessageFormat::Json),
+          None => Ok(MessageFormat::Legacy),
+        };
+        let deflate = negotiate(
+          request.header("Sec-WebSocket-Extensions"),
+          config.compression(),
+        );
+        format.and_then(|format| {
+          accept_key(&request).map(|key| (key, leftover, token, protocol, format, deflate))
+        })
+      }
+      Err(err) => Err(err),
+    };
+    match result {
+      Ok((key, leftover, token, protocol, format, deflate)) => {
+        let (params, extensions) = match deflate {
+          Some((params, extensions)) => {
+            debug!("Using {}", extensions);
+            (Some(params), Some(extensions))
           }
+          None => (None, None),
+        };
+        let response =
+          switching_protocols_response(&key, protocol.as_deref(), extensions.as_deref());
+        match stream.write_all(response.as_bytes()).await {
+          Ok(_) => Some(Upgrade {
+            leftover,
+            token,
+            protocol,
+            format,
+            deflate: params,
+          }),
+          Err(_) => None,
         }
       }
       Err(err) => {
-        println!("{}", err);
-        return (None, None);
+        warn!("Invalid client handshake: {:?}", err);
+        Self::reject_connection(stream, err).await;
+        None
       }
     }
   }
 
-  pub async fn write_message(
-    client_ids: Vec<u32>,
-    all_clients: &ClientMap,
-    server_log: &Arc<Mutex<Logger>>,
-    message: &String,
-  ) -> bool {
-    let buf = pack_message_frame(message.clone());
-    debug!("Sending to clients: {:?}", client_ids);
-    let client_map = all_clients.read().await;
-    for client in client_ids {
-      match client_map.get(&client) {
-        Some(client_object_lock) => {
-          let client_object = client_object_lock.lock().await;
-          let mut client_stream = client_object.stream().lock().await;
-          match (*client_stream).write(&buf).await {
-            Ok(_) => {
-              let msg: String = format!("Server Write: {}", message);
-              let m: Message = Message::new(msg.clone(), ErrorLevel::INFO);
-              // let mut logger = server_log.lock().await;
-              // logger.log(m);
+  // Answers with the error's HTTP response, if it has one, and hangs up
+  async fn reject_connection<S: AsyncWriteExt + Unpin>(mut stream: S, err: HandshakeError) {
+    if let Some(response) = err.response() {
+      if stream.write_all(response.as_bytes()).await.is_ok() {
+        let _ = stream.shutdown().await;
+      }
+    }
+  }
+
+  // Only awaits on the socket read, so it is safe to cancel in a select
+  pub async fn read_message(
+    buf: &mut [u8],
+    decoder: &mut FrameDecoder,
+    assembler: &mut MessageAssembler,
+    inflater: &mut Option<Inflater>,
+    stream: &mut ClientReader,
+  ) -> Result<(u8, Option<Payload>), FrameError> {
+    loop {
+      let frame = match decoder.next_frame()? {
+        Some(frame) => frame,
+        None => {
+          match stream.read(buf).await {
+            Ok(size) => {
+              if size == 0 {
+                debug!("size is 0");
+                return Err(FrameError::ConnectionClosed);
+              }
+              decoder.extend(&buf[..size]);
             }
-            Err(_) => {
-              error!("Error writing to client {}, disconnecting", client);
-              client_stream
-                .shutdown()
-                .await
-                .expect("shutdown call failed");
-              return false;
+            Err(err) => {
+              println!("{}", err);
+              return Err(FrameError::ConnectionClosed);
             }
           }
+          continue;
         }
-        None => {
+      };
+
+      if let Some((opcode, payload, compressed)) = assembler.push(frame)? {
+        // the decoder only lets RSV1 through when deflate was negotiated
+        let payload = match inflater {
+          Some(inflater) if compressed => inflater.decompress(&payload)?,
+          _ => payload,
+        };
+        let msg = match opcode {
+          // the whole message is checked at once so code points split across fragments are fine
+          0x1 => Payload::Text(String::from_utf8(payload).map_err(|_| FrameError::InvalidUtf8)?),
+          0x2 => Payload::Binary(payload),
+          // control frame payloads are handed back as is
+          _ => return Ok((opcode, Some(Payload::Binary(payload)))),
+        };
+        return Ok((opcode, Some(msg)));
+      }
+    }
+  }
+
+  pub async fn write_message(
+    route: &Route,
+    sender: SessionKey,
+    registry: &Registry,
+    server_log: &Arc<Mutex<Logger>>,
+    message: &Outgoing,
+  ) -> Vec<(u32, Delivery)> {
+    debug!("Sending to {:?}", route);
+    let client_map = registry.clients.read().await;
+    let (recipients, missing) = resolve(
+      route,
+      sender,
+      &client_map,
+      &*registry.topics.read().await,
+      &*registry.rooms.read().await,
+    );
+    let mut deliveries: Vec<(u32, Delivery)> = Vec::new();
+    if !missing.is_empty() {
+      let mut offline = registry.offline.lock().await;
+      for client in missing {
+        if offline.push(client, message) {
+          debug!("Client {} is offline, keeping the message for it", client);
+          deliveries.push((client, Delivery::Queued));
+        } else {
           error!("Passed invalid client id {}", client);
+          deliveries.push((client, Delivery::UnknownRecipient));
         }
       }
     }
-    true
+    // messages with an id can be answered with receipts by whoever they were handed to
+    let mut receipts = Vec::new();
+    // only queued here, each client's own writer puts it on the wire
+    for (client, client_object_lock) in recipients {
+      let mut client_object = client_object_lock.lock().await;
+      let payload = message.render(*client_object.format());
+      let delivery = match client_object.send(&payload) {
+        Push::Queued => {
+          let msg: String = format!("Server Write: {}", payload);
+          let m: Message = Message::new(msg.clone(), ErrorLevel::INFO);
+          // let mut logger = server_log.lock().await;
+          // logger.log(m);
+          if let Outgoing::Envelope {
+            from,
+            id: Some(message_id),
+            ..
+          } = message
+          {
+            receipts.push((client, message_id, *from));
+          }
+          Delivery::Delivered
+        }
+        Push::Full | Push::Disconnect => {
+          warn!(
+            "Outbound queue for client {} is full, dropped a message",
+            client
+          );
+          Delivery::QueueFull
+        }
+        // the recipient's writer has failed, its own task takes it out of the map once it notices
+        // and nobody else is affected, the sender included
+        Push::Closed => {
+          error!("Error writing to client {}, dropping the message", client);
+          Delivery::Unreachable
+        }
+      };
+      match deliveries.iter_mut().find(|(id, _)| *id == client) {
+        Some((_, best)) => *best = (*best).min(delivery),
+        None => deliveries.push((client, delivery)),
+      }
+    }
+    if !receipts.is_empty() {
+      let mut delivered = registry.receipts.lock().await;
+      for (recipient, message_id, sender) in receipts {
+        delivered.record(recipient, message_id, sender);
+      }
+    }
+    deliveries.sort_unstable_by_key(|(client, _)| *client);
+    deliveries
   }
 
-  /*async fn send_heartbeat(stream: &mut TcpStream) {
-    // let mut unwrap_stream = Arc::try_unwrap(stream).unwrap().into_inner().unwrap();
-    loop {
-      Self::send_control_frame(stream, 0x9).await;
-      tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+  // Names the recipients that couldn't be written to, for senders that didn't ask for a report
+  async fn report_unreachable(
+    clients: &ClientMap,
+    sender: SessionKey,
+    deliveries: &[(u32, Delivery)],
+  ) {
+    let unreachable: Vec<u32> = deliveries
+      .iter()
+      .filter(|(_, delivery)| *delivery == Delivery::Unreachable)
+      .map(|(recipient, _)| *recipient)
+      .collect();
+    if unreachable.is_empty() {
+      return;
     }
-  }*/
+    let (client, session) = sender;
+    let notice = reply(
+      "error",
+      json!({ "error": "recipients unreachable", "clients": unreachable }),
+      &None,
+    );
+    Self::send_to_session(clients, client, session, &notice).await;
+  }
 
-  async fn send_control_frame(stream: &mut OwnedWriteHalf, opcode: u8) {
-    let byte_msg: Vec<u8> = vec![0b10000000 + opcode];
-    if opcode == 0x9 {
-      // Self::send_heartbeat(stream);
+  // Tells the sender of a message with an id how it went for each recipient
+  async fn report_delivery(
+    clients: &ClientMap,
+    sender: SessionKey,
+    id: &Value,
+    deliveries: &[(u32, Delivery)],
+  ) {
+    let (client, session) = sender;
+    let results: Vec<Value> = deliveries
+      .iter()
+      .map(|(recipient, delivery)| json!({ "client": recipient, "status": delivery.name() }))
+      .collect();
+    let report = reply("delivery", json!({ "results": results }), &Some(id.clone()));
+    Self::send_to_session(clients, client, session, &report).await;
+  }
+
+  // Sends a message to one session of a client, for replies that only its sender should see
+  async fn send_to_session(clients: &ClientMap, id: u32, session: u64, message: &Payload) {
+    let client_map = clients.read().await;
+    if let Some(client_object_lock) = client_map
+      .get(&id)
+      .and_then(|sessions| sessions.get(&session))
+    {
+      if client_object_lock.lock().await.send(message) != Push::Queued {
+        error!("Error replying to client {}", id);
+      }
     }
-    match stream.write(&byte_msg).await {
+  }
+
+  // Tells the rest of a room that a client joined or left it
+  async fn announce(
+    registry: &Registry,
+    server_log: &Arc<Mutex<Logger>>,
+    sender: SessionKey,
+    room: String,
+    event: Presence,
+  ) {
+    let (client, _) = sender;
+    let route = Route::Room(room.clone());
+    let presence = Outgoing::Presence {
+      client,
+      room,
+      event,
+    };
+    Self::write_message(&route, sender, registry, server_log, &presence).await;
+  }
+
+  async fn log_read(server_log: &Arc<Mutex<Logger>>, data: &Option<Payload>) {
+    if let Some(msg) = data {
+      let log_msg: String = format!("Server Read: {}", msg);
+      let m: Message = Message::new(log_msg, ErrorLevel::INFO);
+      let mut logger = server_log.lock().await;
+      logger.log(m);
+    }
+  }
+
+  // Counts a heartbeat ping against the client, false once it has missed too many pongs
+  async fn record_ping(clients: &ClientMap, id: u32, session: u64, max_missed_pongs: u32) -> bool {
+    let client_map = clients.read().await;
+    match client_map
+      .get(&id)
+      .and_then(|sessions| sessions.get(&session))
+    {
+      Some(client_object_lock) => {
+        let mut client_object = client_object_lock.lock().await;
+        if *client_object.missed_pongs() >= max_missed_pongs {
+          warn!(
+            "Client {} missed {} pongs, last one {:?} ago, disconnecting",
+            id,
+            client_object.missed_pongs(),
+            client_object.last_pong_time().elapsed()
+          );
+          return false;
+        }
+        client_object.record_ping();
+        true
+      }
+      None => false,
+    }
+  }
+
+  async fn record_pong(clients: &ClientMap, id: u32, session: u64) {
+    let client_map = clients.read().await;
+    if let Some(client_object_lock) = client_map
+      .get(&id)
+      .and_then(|sessions| sessions.get(&session))
+    {
+      client_object_lock.lock().await.record_pong();
+    }
+  }
+
+  async fn write_close_frame(stream: &mut ClientWriter, close: &CloseReason) {
+    match stream.write_all(&pack_close_frame(close)).await {
       Ok(_) => {
-        debug!("Server sent opcode {}", opcode);
+        debug!("Server sent close frame {}", close);
       }
       Err(_) => {
-        error!("Failed to send server control frame, opcode {}", opcode);
+        error!("Failed to send server close frame {}", close);
       }
     }
   }
 
+  async fn send_close_frame(stream: &mut ClientWriter, err: FrameError) {
+    let code = match err.close_code() {
+      Some(code) => code,
+      None => return,
+    };
+    warn!("Closing client connection with code {}: {:?}", code, err);
+    Self::write_close_frame(stream, &CloseReason::new(code, "")).await;
+  }
+
+  // Once a client is registered everything goes through its outbound queue, so control frames
+  // stay in order with the messages around them
+  fn queue_close_frame(outbound: &Outbound, close: &CloseReason) {
+    match outbound.push_control(pack_close_frame(close)) {
+      Push::Queued => debug!("Server queued close frame {}", close),
+      _ => error!("Failed to send server close frame {}", close),
+    }
+  }
+
+  fn queue_error_close(outbound: &Outbound, err: FrameError) {
+    let code = match err.close_code() {
+      Some(code) => code,
+      None => return,
+    };
+    warn!("Closing client connection with code {}: {:?}", code, err);
+    Self::queue_close_frame(outbound, &CloseReason::new(code, ""));
+  }
+
+  fn queue_control_frame(outbound: &Outbound, opcode: u8, payload: &[u8]) {
+    match outbound.push_control(pack_frame(opcode, payload)) {
+      Push::Queued => debug!("Server queued opcode {}", opcode),
+      _ => error!("Failed to send server control frame, opcode {}", opcode),
+    }
+  }
+
   pub async fn handle_client(
     server_log: &Arc<Mutex<Logger>>,
-    mut stream: TcpStream,
-    clients: ClientMap,
+    stream: TcpStream,
+    tls_acceptor: Option<TlsAcceptor>,
+    registry: Registry,
+    config: Arc<ServerConfig>,
+    protocols: Arc<Vec<String>>,
+  ) {
+    if let Some(mut stream) = Self::accept_stream(stream, tls_acceptor).await {
+      if let Some(upgrade) = Self::verify_client_handshake(&mut stream, &config, &protocols).await {
+        Self::serve_client(server_log, stream, upgrade, &registry, &config).await;
+      }
+    }
+    info!("Client all done");
+    let logger = server_log.lock().await;
+    logger.print_log().unwrap();
+  }
+
+  async fn serve_client(
+    server_log: &Arc<Mutex<Logger>>,
+    stream: Box<dyn ClientStream>,
+    upgrade: Upgrade,
+    registry: &Registry,
+    config: &ServerConfig,
   ) {
+    let Upgrade {
+      leftover,
+      token,
+      protocol,
+      format,
+      deflate,
+    } = upgrade;
+    let clients = &registry.clients;
+    let topics = &registry.topics;
+    let rooms = &registry.rooms;
+    let close_timeout = config.limits().close_timeout();
+    let heartbeat_interval = config.heartbeat().interval();
+    let max_missed_pongs = *config.heartbeat().max_missed_pongs();
+    let notify_unreachable = *config.outbound().notify_unreachable();
     let mut buf: Vec<u8> = vec![0; 1024];
-    let handshake_success: bool = Self::verify_client_handshake(&mut stream).await;
-    if handshake_success {
-      let (mut read_half, write_half) = stream.into_split();
-      let (_, first_data) = Self::read_message(server_log, &mut buf, &mut read_half).await;
-      debug!("First data: {:?}", first_data);
-      let id = first_data.unwrap().parse::<u32>().expect("Invalid id");
-      let mut client_map = clients.write().await;
-
-      let write_half_arc = Arc::new(Mutex::new(write_half));
-      client_map.insert(
-        id,
-        Mutex::new(ConnectedClient::new(id, Arc::clone(&write_half_arc))),
+    let max_message_size = *config.limits().max_message_size();
+    let mut decoder = FrameDecoder::new(deflate.is_some(), *config.limits().max_frame_size());
+    decoder.extend(&leftover);
+    let mut assembler = MessageAssembler::new(max_message_size);
+    let mut inflater = deflate
+      .as_ref()
+      .map(|params| Inflater::new(params, max_message_size));
+    let (mut read_half, mut write_half) = tokio::io::split(stream);
+    // without a token in the query string the first message has to be one. It isn't logged since
+    // anyone who sees it can register as that client until it expires.
+    let token = match token {
+      Some(token) => Some(token),
+      None => match tokio::time::timeout(
+        REQUEST_TIMEOUT,
+        Self::read_message(
+          &mut buf,
+          &mut decoder,
+          &mut assembler,
+          &mut inflater,
+          &mut read_half,
+        ),
+      )
+      .await
+      {
+        Ok(Ok((_, first_data))) => first_data.and_then(Payload::into_text),
+        Ok(Err(err)) => {
+          Self::send_close_frame(&mut write_half, err).await;
+          return;
+        }
+        Err(_) => None,
+      },
+    };
+    let id = match token
+      .ok_or(AuthError::Missing)
+      .and_then(|token| verify_token(config.auth().key(), &token))
+    {
+      Ok(id) => id,
+      Err(err) => {
+        warn!("Rejecting client registration: {}", err);
+        let close = CloseReason::new(CLOSE_POLICY_VIOLATION, "Authentication failed");
+        Self::write_close_frame(&mut write_half, &close).await;
+        return;
+      }
+    };
+    match &protocol {
+      Some(protocol) => info!("Client {} speaks {}", id, protocol),
+      None => debug!("Client {} did not pick a subprotocol", id),
+    }
+    let session = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+    let mut client_map = clients.write().await;
+    if let Some(sessions) = client_map.get_mut(&id) {
+      match config.auth().duplicate_ids() {
+        DuplicateIdPolicy::Reject => {
+          std::mem::drop(client_map);
+          warn!(
+            "Client {} is already connected, turning the new session away",
+            id
+          );
+          let close = CloseReason::new(CLOSE_POLICY_VIOLATION, "Client ID already connected");
+          Self::write_close_frame(&mut write_half, &close).await;
+          return;
+        }
+        DuplicateIdPolicy::Replace => {
+          info!("Client {} connected again, closing its old session", id);
+          // out of the map right away so messages only reach the new session
+          for (_, old_session) in sessions.drain() {
+            old_session.into_inner().request_close(CloseReason::new(
+              CLOSE_POLICY_VIOLATION,
+              "Replaced by a new session",
+            ));
+          }
+        }
+        DuplicateIdPolicy::Multiple => debug!("Client {} opened another session", id),
+      }
+    }
+
+    let outbound = Arc::new(Outbound::new(
+      *config.outbound().queue_size(),
+      *config.outbound().overflow(),
+    ));
+    let writer = {
+      let outbound = Arc::clone(&outbound);
+      let deflater = deflate.as_ref().map(Deflater::new);
+      tokio::spawn(async move { outbound.run(write_half, deflater).await })
+    };
+    let (close_tx, mut close_rx) = mpsc::unbounded_channel();
+    let mut client = ConnectedClient::new(id, Arc::clone(&outbound), close_tx, format, protocol);
+    // taken under the map lock so nothing gets queued for the id once it is back, and handed to
+    // the session before it is in the map so live messages can't overtake them
+    let queued = registry.offline.lock().await.take(id);
+    if !queued.is_empty() {
+      info!(
+        "Delivering {} queued messages to client {}",
+        queued.len(),
+        id
       );
-      std::mem::drop(client_map);
+    }
+    for message in queued {
+      if client.send(&message.render(format)) != Push::Queued {
+        error!("Error delivering a queued message to client {}", id);
+      } else if let Outgoing::Envelope {
+        from,
+        id: Some(message_id),
+        ..
+      } = &message
+      {
+        registry.receipts.lock().await.record(id, message_id, *from);
+      }
+    }
+    client_map
+      .entry(id)
+      .or_default()
+      .insert(session, Mutex::new(client));
+    std::mem::drop(client_map);
 
-      loop {
-        let (opcode, data) = Self::read_message(server_log, &mut buf, &mut read_half).await;
-        if opcode.is_none() {
-          break;
+    // set once we have sent a close frame and are waiting for the client to echo it
+    let mut close_deadline: Option<Instant> = None;
+    // what the client's rooms are told when it goes
+    let mut departure = Presence::Leave;
+    // set while the client is over its rate and nothing is read from it
+    let mut throttled: Option<Instant> = None;
+    // a zero interval turns the heartbeat off
+    let heartbeat_enabled = !heartbeat_interval.is_zero();
+    let heartbeat_period = heartbeat_interval.max(Duration::from_millis(1));
+    let mut heartbeat =
+      tokio::time::interval_at(Instant::now() + heartbeat_period, heartbeat_period);
+    loop {
+      let result = tokio::select! {
+        result = Self::read_message(
+          &mut buf,
+          &mut decoder,
+          &mut assembler,
+          &mut inflater,
+          &mut read_half,
+        ), if throttled.is_none() => result,
+        _ = tokio::time::sleep_until(throttled.unwrap_or_else(Instant::now)),
+          if throttled.is_some() => {
+          throttled = None;
+          continue;
         }
-        let opcode_val = opcode.unwrap();
-        if opcode_val == 0x8 {
-          info!("Server received opcode 8");
-          let mut wh = write_half_arc.lock().await;
-          Self::send_control_frame(&mut wh, opcode_val).await;
+        Some(close) = close_rx.recv(), if close_deadline.is_none() => {
+          info!("Closing client {}: {}", id, close);
+          Self::queue_close_frame(&outbound, &close);
+          close_deadline = Some(Instant::now() + close_timeout);
+          continue;
+        }
+        _ = tokio::time::sleep_until(close_deadline.unwrap_or_else(Instant::now)),
+          if close_deadline.is_some() => {
+          warn!("Client {} did not answer close frame in time", id);
+          departure = Presence::Timeout;
           break;
-        } else if opcode_val == 0x9 {
-          let mut wh = write_half_arc.lock().await;
-          Self::send_control_frame(&mut wh, 0xA).await;
-        } else if opcode_val == 0x1 {
-          let unwrapped_data = data.unwrap();
-          let split_data: Vec<&str> = unwrapped_data.split(',').collect();
-          let text_message = String::from(split_data[split_data.len() - 1]);
-          let ids: Vec<u32> = split_data[0..split_data.len() - 1]
-            .iter()
-            .map(|s| s.parse::<u32>().unwrap())
-            .collect();
-          if !Self::write_message(ids, &clients, server_log, &text_message).await {
+        }
+        _ = heartbeat.tick(), if heartbeat_enabled && close_deadline.is_none() => {
+          if !Self::record_ping(clients, id, session, max_missed_pongs).await {
+            departure = Presence::Timeout;
             break;
           }
-        } else {
+          Self::queue_control_frame(&outbound, 0x9, &[]);
+          continue;
+        }
+        _ = outbound.stopped() => {
+          warn!("Lost the connection to client {}", id);
+          break;
+        }
+      };
+      let (opcode_val, data) = match result {
+        Ok(message) => message,
+        Err(err) => {
+          Self::queue_error_close(&outbound, err);
           break;
         }
+      };
+      if opcode_val == 0x8 {
+        match unpack_close_payload(data.unwrap().as_bytes()) {
+          Ok(close) => {
+            if close_deadline.is_none() {
+              info!("Client {} closed the connection: {}", id, close);
+              Self::queue_close_frame(&outbound, &close);
+            } else {
+              info!("Client {} acknowledged close: {}", id, close);
+            }
+          }
+          Err(err) => Self::queue_error_close(&outbound, err),
+        }
+        break;
+      } else if close_deadline.is_some() {
+        // nothing more gets sent once a close frame is out, so drop anything else
+        continue;
+      } else if opcode_val == 0x9 {
+        // pongs carry the ping's application data back
+        Self::queue_control_frame(&outbound, 0xA, data.unwrap().as_bytes());
+      } else if opcode_val == 0xA {
+        debug!("Server received pong from client {}", id);
+        Self::record_pong(clients, id, session).await;
+      } else if opcode_val == 0x1 || opcode_val == 0x2 {
+        let size = data.as_ref().map_or(0, |data| data.as_bytes().len());
+        match registry.rates.lock().await.check(id, size) {
+          Verdict::Allow => {}
+          Verdict::Drop => {
+            debug!("Client {} is over its rate, dropping a message", id);
+            continue;
+          }
+          Verdict::Delay(wait) => {
+            debug!("Client {} is over its rate, pausing for {:?}", id, wait);
+            throttled = Some(Instant::now() + wait);
+          }
+          Verdict::Close => {
+            warn!("Client {} is over its rate, closing it", id);
+            Self::queue_close_frame(
+              &outbound,
+              &CloseReason::new(CLOSE_POLICY_VIOLATION, "Rate limit exceeded"),
+            );
+            close_deadline = Some(Instant::now() + close_timeout);
+            continue;
+          }
+        }
+        Self::log_read(server_log, &data).await;
+        let (route, message) = match data.unwrap() {
+          Payload::Text(unwrapped_data) if format == MessageFormat::Json => {
+            match parse_envelope(&unwrapped_data).and_then(|envelope| envelope.into_request(id)) {
+              Ok(Request::Send(route, message)) => (route, message),
+              Ok(Request::Subscribe {
+                topic,
+                id: message_id,
+              }) => {
+                if topics.write().await.subscribe(&topic, (id, session)) {
+                  debug!("Client {} subscribed to {}", id, topic);
+                }
+                let reply = reply("subscribed", json!({ "topic": topic }), &message_id);
+                Self::send_to_session(clients, id, session, &reply).await;
+                continue;
+              }
+              Ok(Request::Unsubscribe {
+                topic,
+                id: message_id,
+              }) => {
+                if topics.write().await.unsubscribe(&topic, (id, session)) {
+                  debug!("Client {} unsubscribed from {}", id, topic);
+                }
+                let reply = reply("unsubscribed", json!({ "topic": topic }), &message_id);
+                Self::send_to_session(clients, id, session, &reply).await;
+                continue;
+              }
+              Ok(Request::Join {
+                room,
+                id: message_id,
+              }) => {
+                let (joined, members) = {
+                  let mut rooms = rooms.write().await;
+                  let joined = rooms.join(&room, (id, session));
+                  (joined, rooms.members(&room))
+                };
+                let reply = reply(
+                  "joined",
+                  json!({ "room": room, "members": members }),
+                  &message_id,
+                );
+                Self::send_to_session(clients, id, session, &reply).await;
+                if joined {
+                  info!("Client {} joined room {}", id, room);
+                  Self::announce(registry, server_log, (id, session), room, Presence::Join).await;
+                }
+                continue;
+              }
+              Ok(Request::Leave {
+                room,
+                id: message_id,
+              }) => {
+                let left = rooms.write().await.leave(&room, (id, session));
+                let reply = reply("left", json!({ "room": room }), &message_id);
+                Self::send_to_session(clients, id, session, &reply).await;
+                if left {
+                  info!("Client {} left room {}", id, room);
+                  Self::announce(registry, server_log, (id, session), room, Presence::Leave).await;
+                }
+                continue;
+              }
+              Ok(Request::Receipt {
+                to,
+                kind,
+                id: message_id,
+              }) => {
+                let mut senders = registry.receipts.lock().await.senders(id, &message_id);
+                if let Some(to) = to {
+                  senders.retain(|sender| to.contains(sender));
+                }
+                if senders.is_empty() {
+                  debug!("Client {} sent a {} for an id it wasn't sent", id, kind);
+                  let reason = format!("{} answers no message delivered to this client", kind);
+                  let reply = reply("error", json!({ "error": reason }), &Some(message_id));
+                  Self::send_to_session(clients, id, session, &reply).await;
+                  continue;
+                }
+                let receipt = Outgoing::Receipt {
+                  from: id,
+                  kind,
+                  id: message_id,
+                };
+                (Route::Clients(senders), receipt)
+              }
+              Err(err) => {
+                warn!("Malformed envelope from client {}: {}", id, err.reason);
+                Self::send_to_session(clients, id, session, &err.reply()).await;
+                continue;
+              }
+            }
+          }
+          Payload::Text(unwrapped_data) => {
+            let (ids, text_message) = parse_legacy(&unwrapped_data);
+            let payload = Payload::Text(text_message);
+            (Route::Clients(ids), Outgoing::Raw { from: id, payload })
+          }
+          Payload::Binary(unwrapped_data) => match unpack_binary_message(&unwrapped_data) {
+            Some((ids, binary_message)) => {
+              let payload = Payload::Binary(binary_message);
+              (Route::Clients(ids), Outgoing::Raw { from: id, payload })
+            }
+            None => {
+              warn!("Malformed binary message from client {}", id);
+              continue;
+            }
+          },
+        };
+        let deliveries =
+          Self::write_message(&route, (id, session), registry, server_log, &message).await;
+        if let Some(message_id) = message.report_id() {
+          Self::report_delivery(clients, (id, session), message_id, &deliveries).await;
+        } else if format == MessageFormat::Json && notify_unreachable {
+          Self::report_unreachable(clients, (id, session), &deliveries).await;
+        }
+      } else {
+        break;
       }
+    }
 
-      let mut client_map = clients.write().await;
-      client_map.remove(&id);
-    } else {
-      warn!("Invalid client handshake");
+    // only this connection's session, the id may have others or a newer one by now
+    let mut client_map = clients.write().await;
+    if let Some(sessions) = client_map.get_mut(&id) {
+      sessions.remove(&session);
+      if sessions.is_empty() {
+        client_map.remove(&id);
+        registry.offline.lock().await.track(id);
+        registry.rates.lock().await.release();
+        registry.receipts.lock().await.forget(id);
+      }
+    }
+    std::mem::drop(client_map);
+    topics.write().await.remove_session((id, session));
+    let left_rooms = rooms.write().await.remove_session((id, session));
+    for room in left_rooms {
+      info!("Client {} left room {}: {}", id, room, departure.name());
+      Self::announce(registry, server_log, (id, session), room, departure).await;
+    }
+    // out of the map, so nothing new gets queued. The writer gets a moment to flush what is
+    // left, the close frame included, before the connection is shut down.
+    outbound.close();
+    let writer_abort = writer.abort_handle();
+    if tokio::time::timeout(close_timeout, writer).await.is_err() {
+      debug!("Client {} did not take its last messages in time", id);
+      writer_abort.abort();
     }
-    info!("Client all done");
-    let logger = server_log.lock().await;
-    logger.print_log().unwrap();
   }
 }
@@ -1,55 +1,90 @@
+use crate::server::frame::CLOSE_POLICY_VIOLATION;
+use crate::server::frame::{CloseReason, Payload};
+use crate::server::message::MessageFormat;
+use crate::server::outbound::{Outbound, OutboundStats, Push};
 use getset::Getters;
 use std::sync::Arc;
-use tokio::net::tcp::OwnedWriteHalf;
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::time::Instant;
+use tracing::{debug, warn};
 
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::UnboundedSender;
 
-#[derive(Debug, Getters)]
+// Plain TCP or TLS, everything past the accept only needs the byte stream
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> ClientStream for S {}
+
+pub type ClientReader = ReadHalf<Box<dyn ClientStream>>;
+pub type ClientWriter = WriteHalf<Box<dyn ClientStream>>;
+
+#[derive(Getters)]
 pub struct ConnectedClient {
   id: u32,
-  heartbeat_status: bool,
-  connected_status: bool,
-  last_ping_time: u32,
   #[getset(get = "pub")]
-  stream: Arc<Mutex<OwnedWriteHalf>>,
+  last_pong_time: Instant,
+  #[getset(get = "pub")]
+  missed_pongs: u32,
+  outbound: Arc<Outbound>,
+  close_requests: UnboundedSender<CloseReason>,
+  // what the client's text messages look like, legacy or JSON envelopes
+  #[getset(get = "pub")]
+  format: MessageFormat,
+  // the subprotocol picked from Sec-WebSocket-Protocol, if the client offered one we know
+  #[getset(get = "pub")]
+  protocol: Option<String>,
 }
 
 impl ConnectedClient {
-  pub fn new(id: u32, stream: Arc<Mutex<OwnedWriteHalf>>) -> ConnectedClient {
-    let client = ConnectedClient {
+  pub fn new(
+    id: u32,
+    outbound: Arc<Outbound>,
+    close_requests: UnboundedSender<CloseReason>,
+    format: MessageFormat,
+    protocol: Option<String>,
+  ) -> ConnectedClient {
+    ConnectedClient {
       id,
-      heartbeat_status: false,
-      connected_status: true,
-      last_ping_time: 0,
-      stream: Arc::clone(&stream),
-    };
-    let client_arc = Arc::new(Mutex::new(client));
-    //let cloned_client = Arc::clone(&client_arc);
-    /*tokio::spawn(async move {
-      let mut client = cloned_client.lock().await;
-      client.acknowledge_heartbeat().await;
-    });*/
-
-    Arc::try_unwrap(client_arc).unwrap().into_inner()
+      last_pong_time: Instant::now(),
+      missed_pongs: 0,
+      outbound,
+      close_requests,
+      format,
+      protocol,
+    }
+  }
+
+  // Asks the connection's task to start the close handshake, false if it has already finished
+  pub fn request_close(&self, close: CloseReason) -> bool {
+    debug!("Requesting close of client {}: {}", self.id, close);
+    self.close_requests.send(close).is_ok()
   }
 
-  //pub fn send_message(&self) {}
-
-  /*pub async fn acknowledge_heartbeat(&mut self) {
-    loop {
-      tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-
-      let mut stream = self.stream.lock().await;
-      match stream.read(&mut [0; 128]).await {
-        Ok(_) => {
-          self.heartbeat_status = true;
-        }
-        Err(e) => {
-          eprintln!("Error reading from stream: {}", e);
-          self.connected_status = false;
-          break;
-        }
-      }
+  // Queues a message for the client's writer. A slow client is closed when the overflow policy
+  // says so.
+  pub fn send(&mut self, message: &Payload) -> Push {
+    let result = self.outbound.push(message.clone());
+    if result == Push::Disconnect {
+      warn!("Client {} is not keeping up, disconnecting", self.id);
+      self.request_close(CloseReason::new(
+        CLOSE_POLICY_VIOLATION,
+        "Too slow reading messages",
+      ));
     }
-  }*/
+    result
+  }
+
+  pub fn outbound_stats(&self) -> OutboundStats {
+    self.outbound.stats()
+  }
+
+  // Counts a heartbeat ping as missed until the matching pong comes back
+  pub fn record_ping(&mut self) {
+    self.missed_pongs += 1;
+  }
+
+  pub fn record_pong(&mut self) {
+    self.last_pong_time = Instant::now();
+    self.missed_pongs = 0;
+  }
 }
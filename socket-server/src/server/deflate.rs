@@ -0,0 +1,356 @@
+use crate::server::frame::FrameError;
+use crate::utils::config::CompressionConfig;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+pub const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+// every compressed message ends with an empty stored block that is left off on the wire
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+// zlib cannot write raw deflate with an 8 bit window, so 9 is the smallest we can honour
+const MIN_WINDOW_BITS: u8 = 9;
+const MAX_WINDOW_BITS: u8 = 15;
+
+// The permessage-deflate parameters both sides agreed on (RFC 7692)
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeflateParams {
+  pub server_no_context_takeover: bool,
+  pub client_no_context_takeover: bool,
+  pub server_max_window_bits: u8,
+  pub client_max_window_bits: u8,
+}
+
+impl DeflateParams {
+  // Value for the Sec-WebSocket-Extensions response header. The window bits only go back to the
+  // client when its offer mentioned them.
+  fn response(&self, offered_server_bits: bool, offered_client_bits: bool) -> String {
+    let mut response = String::from(PERMESSAGE_DEFLATE);
+    if self.server_no_context_takeover {
+      response.push_str("; server_no_context_takeover");
+    }
+    if self.client_no_context_takeover {
+      response.push_str("; client_no_context_takeover");
+    }
+    if offered_server_bits {
+      response.push_str(&format!(
+        "; server_max_window_bits={}",
+        self.server_max_window_bits
+      ));
+    }
+    if offered_client_bits && self.client_max_window_bits < MAX_WINDOW_BITS {
+      response.push_str(&format!(
+        "; client_max_window_bits={}",
+        self.client_max_window_bits
+      ));
+    }
+    response
+  }
+}
+
+fn parse_window_bits(value: Option<&str>) -> Option<u8> {
+  match value?.trim_matches('"').parse::<u8>() {
+    Ok(bits) if (8..=MAX_WINDOW_BITS).contains(&bits) => Some(bits),
+    _ => None,
+  }
+}
+
+// Checks one permessage-deflate offer against our settings, None if we have to decline it
+fn accept_offer(offer: &str, config: &CompressionConfig) -> Option<(DeflateParams, String)> {
+  let mut params = offer.split(';').map(|param| param.trim());
+  if params.next()? != PERMESSAGE_DEFLATE {
+    return None;
+  }
+  let mut agreed = DeflateParams {
+    server_no_context_takeover: *config.server_no_context_takeover(),
+    client_no_context_takeover: *config.client_no_context_takeover(),
+    server_max_window_bits: *config.server_max_window_bits(),
+    client_max_window_bits: MAX_WINDOW_BITS,
+  };
+  let mut seen: Vec<&str> = Vec::new();
+  let mut offered_server_bits = false;
+  let mut offered_client_bits = false;
+  for param in params {
+    let (name, value) = match param.split_once('=') {
+      Some((name, value)) => (name.trim(), Some(value.trim())),
+      None => (param, None),
+    };
+    // the same parameter twice makes the whole offer invalid
+    if seen.contains(&name) {
+      return None;
+    }
+    seen.push(name);
+    match name {
+      "server_no_context_takeover" if value.is_none() => agreed.server_no_context_takeover = true,
+      "client_no_context_takeover" if value.is_none() => agreed.client_no_context_takeover = true,
+      "server_max_window_bits" => {
+        let bits = parse_window_bits(value)?;
+        agreed.server_max_window_bits = agreed.server_max_window_bits.min(bits);
+        offered_server_bits = true;
+      }
+      "client_max_window_bits" => {
+        // without a value the client just says it can limit its window
+        let bits = match value {
+          Some(_) => parse_window_bits(value)?,
+          None => MAX_WINDOW_BITS,
+        };
+        agreed.client_max_window_bits = (*config.client_max_window_bits()).min(bits);
+        offered_client_bits = true;
+      }
+      _ => return None,
+    }
+  }
+  if agreed.server_max_window_bits < MIN_WINDOW_BITS {
+    return None;
+  }
+  let response = agreed.response(offered_server_bits, offered_client_bits);
+  Some((agreed, response))
+}
+
+// Picks the first permessage-deflate offer in the client's Sec-WebSocket-Extensions header that
+// we can accept and returns it with the header value to answer with
+pub fn negotiate(
+  extensions: Option<&str>,
+  config: &CompressionConfig,
+) -> Option<(DeflateParams, String)> {
+  if !*config.permessage_deflate() {
+    return None;
+  }
+  extensions?
+    .split(',')
+    .find_map(|offer| accept_offer(offer.trim(), config))
+}
+
+// Compresses outgoing messages, one per connection since the window carries over between
+// messages unless context takeover is off
+pub struct Deflater {
+  compress: Compress,
+  no_context_takeover: bool,
+}
+
+impl Deflater {
+  pub fn new(params: &DeflateParams) -> Deflater {
+    Deflater {
+      compress: Compress::new_with_window_bits(
+        Compression::default(),
+        false,
+        params.server_max_window_bits,
+      ),
+      no_context_takeover: params.server_no_context_takeover,
+    }
+  }
+
+  pub fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(data.len() / 2 + 64);
+    let start = self.compress.total_in();
+    loop {
+      let consumed = (self.compress.total_in() - start) as usize;
+      if out.len() == out.capacity() {
+        out.reserve(out.capacity());
+      }
+      self
+        .compress
+        .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+        .expect("deflate failed");
+      // done once all input is in and the flush did not run out of room
+      if (self.compress.total_in() - start) as usize == data.len() && out.len() < out.capacity() {
+        break;
+      }
+    }
+    if out.ends_with(&DEFLATE_TAIL) {
+      out.truncate(out.len() - DEFLATE_TAIL.len());
+    }
+    if self.no_context_takeover {
+      self.compress.reset();
+    }
+    out
+  }
+}
+
+// Decompresses incoming messages. The window is always the largest possible, which can read
+// anything the client was allowed to send.
+pub struct Inflater {
+  decompress: Decompress,
+  no_context_takeover: bool,
+  max_message_size: usize,
+}
+
+impl Inflater {
+  pub fn new(params: &DeflateParams, max_message_size: usize) -> Inflater {
+    Inflater {
+      decompress: Decompress::new_with_window_bits(false, MAX_WINDOW_BITS),
+      no_context_takeover: params.client_no_context_takeover,
+      max_message_size,
+    }
+  }
+
+  pub fn decompress(&mut self, data: &[u8]) -> Result<Vec<u8>, FrameError> {
+    let mut input: Vec<u8> = Vec::with_capacity(data.len() + DEFLATE_TAIL.len());
+    input.extend_from_slice(data);
+    input.extend_from_slice(&DEFLATE_TAIL);
+    // starts small so a tiny message cannot make us reserve a lot up front
+    let mut out: Vec<u8> = Vec::with_capacity((data.len() * 2).min(self.max_message_size).max(64));
+    let start = self.decompress.total_in();
+    loop {
+      let consumed = (self.decompress.total_in() - start) as usize;
+      if out.len() == out.capacity() {
+        out.reserve(out.capacity());
+      }
+      let written = out.len();
+      let status = self
+        .decompress
+        .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+        .map_err(|_| FrameError::InvalidCompression)?;
+      // checked as it grows so a small message cannot inflate into a huge one
+      if out.len() > self.max_message_size {
+        return Err(FrameError::MessageTooLarge);
+      }
+      let consumed_now = (self.decompress.total_in() - start) as usize;
+      if status == Status::StreamEnd {
+        // the client ended the deflate stream, so the next message starts a new one
+        self.decompress.reset(false);
+        break;
+      }
+      if consumed_now == input.len() && out.len() < out.capacity() {
+        break;
+      }
+      if consumed_now == consumed && out.len() == written && out.len() < out.capacity() {
+        return Err(FrameError::InvalidCompression);
+      }
+    }
+    if self.no_context_takeover {
+      self.decompress.reset(false);
+    }
+    Ok(out)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn config(toml: &str) -> CompressionConfig {
+    toml::from_str(toml).unwrap()
+  }
+
+  fn accept(offer: &str) -> Option<String> {
+    accept_offer(offer, &CompressionConfig::default()).map(|(_, response)| response)
+  }
+
+  #[test]
+  fn accepts_a_plain_offer() {
+    let (params, response) =
+      accept_offer(PERMESSAGE_DEFLATE, &CompressionConfig::default()).unwrap();
+    assert_eq!(
+      params,
+      DeflateParams {
+        server_no_context_takeover: false,
+        client_no_context_takeover: false,
+        server_max_window_bits: 15,
+        client_max_window_bits: 15,
+      }
+    );
+    assert_eq!(response, "permessage-deflate");
+    assert_eq!(
+      accept("permessage-deflate; client_max_window_bits").as_deref(),
+      Some("permessage-deflate")
+    );
+  }
+
+  #[test]
+  fn accepts_offered_parameters() {
+    assert_eq!(
+      accept("permessage-deflate; server_no_context_takeover").as_deref(),
+      Some("permessage-deflate; server_no_context_takeover")
+    );
+    let (params, response) = accept_offer(
+      "permessage-deflate; server_max_window_bits=10; client_max_window_bits=\"12\"",
+      &CompressionConfig::default(),
+    )
+    .unwrap();
+    assert_eq!(
+      (params.server_max_window_bits, params.client_max_window_bits),
+      (10, 12)
+    );
+    assert_eq!(
+      response,
+      "permessage-deflate; server_max_window_bits=10; client_max_window_bits=12"
+    );
+  }
+
+  #[test]
+  fn applies_our_own_limits() {
+    let config = config(
+      "client_no_context_takeover = true\nserver_max_window_bits = 12\nclient_max_window_bits = 10",
+    );
+    let (params, response) = accept_offer(
+      "permessage-deflate; server_max_window_bits=14; client_max_window_bits",
+      &config,
+    )
+    .unwrap();
+    assert_eq!(
+      (params.server_max_window_bits, params.client_max_window_bits),
+      (12, 10)
+    );
+    assert_eq!(
+      response,
+      "permessage-deflate; client_no_context_takeover; server_max_window_bits=12; \
+      client_max_window_bits=10"
+    );
+  }
+
+  #[test]
+  fn declines_invalid_offers() {
+    for offer in [
+      "x-webkit-deflate-frame",
+      "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+      "permessage-deflate; unknown_parameter",
+      "permessage-deflate; server_no_context_takeover=1",
+      "permessage-deflate; server_max_window_bits",
+      "permessage-deflate; server_max_window_bits=16",
+      "permessage-deflate; client_max_window_bits=7",
+      // zlib cannot write with an 8 bit window
+      "permessage-deflate; server_max_window_bits=8",
+    ] {
+      assert_eq!(accept(offer), None, "{}", offer);
+    }
+  }
+
+  #[test]
+  fn negotiates_the_first_acceptable_offer() {
+    let offers = "x-webkit-deflate-frame, permessage-deflate; bogus, \
+      permessage-deflate; server_no_context_takeover, permessage-deflate";
+    let (params, _) = negotiate(Some(offers), &CompressionConfig::default()).unwrap();
+    assert!(params.server_no_context_takeover);
+    assert!(negotiate(None, &CompressionConfig::default()).is_none());
+    assert!(negotiate(
+      Some("permessage-deflate"),
+      &config("permessage_deflate = false")
+    )
+    .is_none());
+  }
+
+  #[test]
+  fn round_trips_messages() {
+    let (params, _) = accept_offer(PERMESSAGE_DEFLATE, &CompressionConfig::default()).unwrap();
+    let mut deflater = Deflater::new(&params);
+    let mut inflater = Inflater::new(&params, 1 << 20);
+    let message = "hello hello hello hello".repeat(100);
+    for _ in 0..3 {
+      let compressed = deflater.compress(message.as_bytes());
+      assert!(compressed.len() < message.len());
+      assert_eq!(
+        inflater.decompress(&compressed).unwrap(),
+        message.as_bytes()
+      );
+    }
+  }
+
+  #[test]
+  fn limits_the_inflated_size() {
+    let (params, _) = accept_offer(PERMESSAGE_DEFLATE, &CompressionConfig::default()).unwrap();
+    let compressed = Deflater::new(&params).compress(&[0; 10000]);
+    let mut inflater = Inflater::new(&params, 1000);
+    assert!(matches!(
+      inflater.decompress(&compressed),
+      Err(FrameError::MessageTooLarge)
+    ));
+  }
+}
@@ -0,0 +1,616 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub struct Frame {
+  pub fin: bool,
+  // set on the first frame of a message compressed with permessage-deflate
+  pub rsv1: bool,
+  pub opcode: u8,
+  pub payload: Vec<u8>,
+}
+
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_NO_STATUS: u16 = 1005;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug)]
+pub enum FrameError {
+  ConnectionClosed,
+  ReservedBits,
+  Unmasked,
+  UnexpectedContinuation,
+  IncompleteMessage,
+  MessageTooLarge,
+  FrameTooLarge,
+  InvalidUtf8,
+  InvalidClosePayload,
+  InvalidControlFrame,
+  InvalidCompression,
+  // one of the opcodes RFC 6455 keeps for later, 0x3-0x7 and 0xB-0xF
+  ReservedOpcode,
+  // a 64-bit length with its top bit set
+  InvalidLength,
+}
+
+impl FrameError {
+  // Status code to close the connection with, None if the peer is already gone
+  pub fn close_code(&self) -> Option<u16> {
+    match self {
+      FrameError::ConnectionClosed => None,
+      FrameError::MessageTooLarge | FrameError::FrameTooLarge => Some(CLOSE_MESSAGE_TOO_BIG),
+      FrameError::InvalidUtf8 => Some(CLOSE_INVALID_DATA),
+      _ => Some(CLOSE_PROTOCOL_ERROR),
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub enum Payload {
+  Text(String),
+  Binary(Vec<u8>),
+}
+
+impl Payload {
+  pub fn opcode(&self) -> u8 {
+    match self {
+      Payload::Text(_) => 0x1,
+      Payload::Binary(_) => 0x2,
+    }
+  }
+
+  pub fn as_bytes(&self) -> &[u8] {
+    match self {
+      Payload::Text(text) => text.as_bytes(),
+      Payload::Binary(bytes) => bytes,
+    }
+  }
+
+  pub fn into_text(self) -> Option<String> {
+    match self {
+      Payload::Text(text) => Some(text),
+      Payload::Binary(_) => None,
+    }
+  }
+}
+
+impl fmt::Display for Payload {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Payload::Text(text) => write!(f, "{}", text),
+      Payload::Binary(bytes) => write!(f, "<{} bytes>", bytes.len()),
+    }
+  }
+}
+
+pub fn pack_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+  // FIN = 1 (only frame in message), RSV1-3 = 0
+  pack_frame_with_first_byte(0b10000000 + opcode, payload)
+}
+
+// Same as pack_frame with RSV1 set to mark the payload as deflated
+pub fn pack_compressed_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+  pack_frame_with_first_byte(0b11000000 + opcode, payload)
+}
+
+fn pack_frame_with_first_byte(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+  let mut frame: Vec<u8> = vec![first_byte];
+  frame.reserve(1024);
+
+  let mut second_byte: u8 = 0;
+  let strlen = payload.len() as u64;
+  let mut len_bytes = 0;
+  if strlen > 65535 {
+    // 8 byte payload len
+    second_byte += 127;
+    len_bytes = 8;
+  } else if strlen > 125 {
+    // 2 byte payload len
+    second_byte += 126;
+    len_bytes = 2;
+  } else {
+    second_byte += strlen as u8;
+  }
+  frame.push(second_byte);
+
+  if len_bytes == 8 {
+    let bytes = u64::to_be_bytes(strlen);
+    frame.extend_from_slice(&bytes);
+  } else if len_bytes == 2 {
+    let bytes = u16::to_be_bytes(strlen as u16);
+    frame.extend_from_slice(&bytes);
+  }
+
+  frame.extend_from_slice(payload);
+  frame
+}
+
+pub fn pack_message_frame(payload: &Payload) -> Vec<u8> {
+  pack_frame(payload.opcode(), payload.as_bytes())
+}
+
+#[derive(Debug, Clone)]
+pub struct CloseReason {
+  pub code: u16,
+  pub reason: String,
+}
+
+impl CloseReason {
+  pub fn new(code: u16, reason: &str) -> CloseReason {
+    CloseReason {
+      code,
+      reason: String::from(reason),
+    }
+  }
+}
+
+impl fmt::Display for CloseReason {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.reason.is_empty() {
+      write!(f, "{}", self.code)
+    } else {
+      write!(f, "{} ({})", self.code, self.reason)
+    }
+  }
+}
+
+fn is_valid_close_code(code: u16) -> bool {
+  matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+pub fn pack_close_frame(close: &CloseReason) -> Vec<u8> {
+  if close.code == CLOSE_NO_STATUS {
+    return pack_frame(0x8, &[]);
+  }
+  let mut payload: Vec<u8> = close.code.to_be_bytes().to_vec();
+  // control frames are capped at 125 bytes, leaving 123 for the reason
+  let mut reason_len = close.reason.len().min(123);
+  while !close.reason.is_char_boundary(reason_len) {
+    reason_len -= 1;
+  }
+  payload.extend_from_slice(&close.reason.as_bytes()[..reason_len]);
+  pack_frame(0x8, &payload)
+}
+
+pub fn unpack_close_payload(payload: &[u8]) -> Result<CloseReason, FrameError> {
+  if payload.is_empty() {
+    return Ok(CloseReason::new(CLOSE_NO_STATUS, ""));
+  }
+  if payload.len() == 1 {
+    return Err(FrameError::InvalidClosePayload);
+  }
+  let code = u16::from_be_bytes([payload[0], payload[1]]);
+  if !is_valid_close_code(code) {
+    return Err(FrameError::InvalidClosePayload);
+  }
+  let reason = String::from_utf8(payload[2..].to_vec()).map_err(|_| FrameError::InvalidUtf8)?;
+  Ok(CloseReason { code, reason })
+}
+
+// Accumulates bytes read off the socket and splits them into complete client frames. Bytes of a
+// frame that has not fully arrived yet stay buffered until the next read.
+#[derive(Debug)]
+pub struct FrameDecoder {
+  buf: Vec<u8>,
+  // RSV1 is only allowed once permessage-deflate has been negotiated
+  allow_rsv1: bool,
+  // largest payload a single frame may declare
+  max_frame_size: usize,
+}
+
+impl FrameDecoder {
+  pub fn new(allow_rsv1: bool, max_frame_size: usize) -> FrameDecoder {
+    FrameDecoder {
+      buf: Vec::with_capacity(1024),
+      allow_rsv1,
+      max_frame_size,
+    }
+  }
+
+  pub fn extend(&mut self, data: &[u8]) {
+    self.buf.extend_from_slice(data);
+  }
+
+  pub fn next_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+    if self.buf.len() < 2 {
+      return Ok(None);
+    }
+    let first_byte = self.buf[0];
+    let fin: bool = (first_byte & 128) >> 7 == 1;
+    let rsv1: bool = first_byte & 0b01000000 != 0;
+    let opcode: u8 = first_byte & 15;
+    if first_byte & 0b00110000 != 0 {
+      return Err(FrameError::ReservedBits);
+    }
+    if !matches!(opcode, 0x0 | 0x1 | 0x2 | 0x8 | 0x9 | 0xA) {
+      return Err(FrameError::ReservedOpcode);
+    }
+    // only the first data frame of a message can say it is compressed
+    if rsv1 && (!self.allow_rsv1 || is_control_opcode(opcode) || opcode == 0x0) {
+      return Err(FrameError::ReservedBits);
+    }
+
+    let second_byte = self.buf[1];
+    let mask: bool = (second_byte & 128) >> 7 == 1;
+    if !mask {
+      // clients must mask stuff
+      return Err(FrameError::Unmasked);
+    }
+    let second_byte_payload_len = second_byte & 127;
+    if is_control_opcode(opcode) && (!fin || second_byte_payload_len > 125) {
+      // control frames are never fragmented and always fit in the second byte's length
+      return Err(FrameError::InvalidControlFrame);
+    }
+    let mut payload_len: u64 = second_byte_payload_len as u64;
+    let mut payload_len_bytes: usize = 0;
+    if second_byte_payload_len == 127 {
+      payload_len_bytes = 8;
+    } else if second_byte_payload_len == 126 {
+      payload_len_bytes = 2;
+    }
+
+    let mask_key_start = payload_len_bytes + 2;
+    let payload_start = mask_key_start + 4;
+    if self.buf.len() < payload_start {
+      return Ok(None);
+    }
+    if payload_len_bytes == 8 {
+      payload_len = u64::from_be_bytes(self.buf[2..10].try_into().unwrap());
+    } else if payload_len_bytes == 2 {
+      payload_len = u16::from_be_bytes(self.buf[2..4].try_into().unwrap()) as u64;
+    }
+    // the length comes from the client, so it is checked before anything waits for that much
+    if payload_len >> 63 != 0 {
+      return Err(FrameError::InvalidLength);
+    }
+    if payload_len > self.max_frame_size as u64 {
+      return Err(FrameError::FrameTooLarge);
+    }
+    if ((self.buf.len() - payload_start) as u64) < payload_len {
+      return Ok(None);
+    }
+
+    let frame_end = payload_start + payload_len as usize;
+    let mut masking_key: [u8; 4] = [0; 4];
+    masking_key.copy_from_slice(&self.buf[mask_key_start..payload_start]);
+    let mut payload: Vec<u8> = self.buf[payload_start..frame_end].to_vec();
+    for (i, byte) in payload.iter_mut().enumerate() {
+      *byte ^= masking_key[i % 4];
+    }
+    self.buf.drain(..frame_end);
+
+    Ok(Some(Frame {
+      fin,
+      rsv1,
+      opcode,
+      payload,
+    }))
+  }
+}
+
+pub fn is_control_opcode(opcode: u8) -> bool {
+  opcode & 0x8 != 0
+}
+
+// Joins a fragmented message back together from its first data frame and the continuation
+// frames (opcode 0x0) that follow it. Control frames may arrive between fragments and are passed
+// straight through. Alongside the opcode and payload it reports whether the message is
+// compressed.
+#[derive(Debug)]
+pub struct MessageAssembler {
+  opcode: Option<u8>,
+  compressed: bool,
+  payload: Vec<u8>,
+  max_message_size: usize,
+}
+
+impl MessageAssembler {
+  pub fn new(max_message_size: usize) -> MessageAssembler {
+    MessageAssembler {
+      opcode: None,
+      compressed: false,
+      payload: Vec::new(),
+      max_message_size,
+    }
+  }
+
+  pub fn push(&mut self, frame: Frame) -> Result<Option<(u8, Vec<u8>, bool)>, FrameError> {
+    if is_control_opcode(frame.opcode) {
+      return Ok(Some((frame.opcode, frame.payload, false)));
+    }
+
+    let opcode = match (frame.opcode, self.opcode) {
+      (0x0, None) => return Err(FrameError::UnexpectedContinuation),
+      (0x0, Some(opcode)) => opcode,
+      (_, Some(_)) => return Err(FrameError::IncompleteMessage),
+      (opcode, None) => opcode,
+    };
+    if self.payload.len() + frame.payload.len() > self.max_message_size {
+      return Err(FrameError::MessageTooLarge);
+    }
+
+    if frame.fin && self.opcode.is_none() {
+      return Ok(Some((opcode, frame.payload, frame.rsv1)));
+    }
+    self.payload.extend_from_slice(&frame.payload);
+    if !frame.fin {
+      if self.opcode.is_none() {
+        self.compressed = frame.rsv1;
+      }
+      self.opcode = Some(opcode);
+      return Ok(None);
+    }
+    self.opcode = None;
+    Ok(Some((
+      opcode,
+      std::mem::take(&mut self.payload),
+      self.compressed,
+    )))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // a client frame with a fixed masking key
+  fn client_frame(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+    let key = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![first_byte];
+    if payload.len() < 126 {
+      frame.push(0x80 | payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+      frame.push(0x80 | 126);
+      frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+      frame.push(0x80 | 127);
+      frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(&key);
+    frame.extend(
+      payload
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ key[i % 4]),
+    );
+    frame
+  }
+
+  fn decoder() -> FrameDecoder {
+    FrameDecoder::new(false, 1 << 20)
+  }
+
+  #[test]
+  fn decodes_a_frame_split_over_reads() {
+    let mut decoder = decoder();
+    let bytes = client_frame(0x81, b"hello");
+    for byte in &bytes[..bytes.len() - 1] {
+      decoder.extend(&[*byte]);
+      assert!(decoder.next_frame().unwrap().is_none());
+    }
+    decoder.extend(&bytes[bytes.len() - 1..]);
+    let frame = decoder.next_frame().unwrap().unwrap();
+    assert_eq!(frame.opcode, 0x1);
+    assert_eq!(frame.payload, b"hello");
+    assert!(decoder.next_frame().unwrap().is_none());
+  }
+
+  #[test]
+  fn decodes_coalesced_frames() {
+    let mut decoder = decoder();
+    let mut bytes = client_frame(0x81, b"one");
+    bytes.extend(client_frame(0x82, &[7; 300]));
+    bytes.extend(client_frame(0x89, b""));
+    decoder.extend(&bytes);
+    assert_eq!(decoder.next_frame().unwrap().unwrap().payload, b"one");
+    let binary = decoder.next_frame().unwrap().unwrap();
+    assert_eq!((binary.opcode, binary.payload.len()), (0x2, 300));
+    assert_eq!(decoder.next_frame().unwrap().unwrap().opcode, 0x9);
+    assert!(decoder.next_frame().unwrap().is_none());
+  }
+
+  #[test]
+  fn decodes_a_64_bit_length() {
+    let mut decoder = decoder();
+    decoder.extend(&client_frame(0x82, &[1; 70000]));
+    assert_eq!(decoder.next_frame().unwrap().unwrap().payload.len(), 70000);
+  }
+
+  #[test]
+  fn rejects_unmasked_frames() {
+    let mut decoder = decoder();
+    decoder.extend(&[0x81, 0x02, b'h', b'i']);
+    assert!(matches!(decoder.next_frame(), Err(FrameError::Unmasked)));
+  }
+
+  #[test]
+  fn rejects_reserved_bits() {
+    let mut decoder = decoder();
+    decoder.extend(&client_frame(0xC1, b"hi"));
+    assert!(matches!(
+      decoder.next_frame(),
+      Err(FrameError::ReservedBits)
+    ));
+  }
+
+  #[test]
+  fn allows_rsv1_on_data_frames_once_negotiated() {
+    let mut decoder = FrameDecoder::new(true, 1 << 20);
+    decoder.extend(&client_frame(0x41, b"x"));
+    decoder.extend(&client_frame(0x80, b"y"));
+    assert!(decoder.next_frame().unwrap().unwrap().rsv1);
+    assert!(!decoder.next_frame().unwrap().unwrap().rsv1);
+    for first_byte in [0xC0, 0xC9, 0xA1, 0x91] {
+      let mut decoder = FrameDecoder::new(true, 1 << 20);
+      decoder.extend(&client_frame(first_byte, b"x"));
+      assert!(
+        matches!(decoder.next_frame(), Err(FrameError::ReservedBits)),
+        "{:#x}",
+        first_byte
+      );
+    }
+  }
+
+  #[test]
+  fn rejects_reserved_opcodes() {
+    for opcode in (0x3..=0x7).chain(0xB..=0xF) {
+      let mut decoder = decoder();
+      decoder.extend(&client_frame(0x80 | opcode, b"hi"));
+      let err = decoder.next_frame().unwrap_err();
+      assert!(matches!(err, FrameError::ReservedOpcode), "{:#x}", opcode);
+      assert_eq!(err.close_code(), Some(CLOSE_PROTOCOL_ERROR));
+    }
+  }
+
+  #[test]
+  fn rejects_bad_control_frames() {
+    let mut decoder = decoder();
+    decoder.extend(&client_frame(0x09, b"ping"));
+    assert!(matches!(
+      decoder.next_frame(),
+      Err(FrameError::InvalidControlFrame)
+    ));
+    let mut decoder = FrameDecoder::new(false, 1 << 20);
+    decoder.extend(&client_frame(0x89, &[0; 126]));
+    assert!(matches!(
+      decoder.next_frame(),
+      Err(FrameError::InvalidControlFrame)
+    ));
+  }
+
+  #[test]
+  fn rejects_an_oversized_frame_from_its_header() {
+    let mut decoder = FrameDecoder::new(false, 1000);
+    // only the header, the declared payload never has to arrive
+    decoder.extend(&[0x82, 0x80 | 127]);
+    decoder.extend(&(1u64 << 40).to_be_bytes());
+    decoder.extend(&[0; 4]);
+    let err = decoder.next_frame().unwrap_err();
+    assert!(matches!(err, FrameError::FrameTooLarge));
+    assert_eq!(err.close_code(), Some(CLOSE_MESSAGE_TOO_BIG));
+  }
+
+  #[test]
+  fn rejects_a_length_with_the_top_bit_set() {
+    let mut decoder = decoder();
+    decoder.extend(&[0x82, 0x80 | 127]);
+    decoder.extend(&(1u64 << 63).to_be_bytes());
+    decoder.extend(&[0; 4]);
+    let err = decoder.next_frame().unwrap_err();
+    assert!(matches!(err, FrameError::InvalidLength));
+    assert_eq!(err.close_code(), Some(CLOSE_PROTOCOL_ERROR));
+  }
+
+  #[test]
+  fn decodes_fragments() {
+    let mut decoder = decoder();
+    decoder.extend(&client_frame(0x01, b"hi"));
+    decoder.extend(&client_frame(0x80, b"!"));
+    let first = decoder.next_frame().unwrap().unwrap();
+    assert_eq!((first.fin, first.opcode), (false, 0x1));
+    let last = decoder.next_frame().unwrap().unwrap();
+    assert_eq!((last.fin, last.opcode), (true, 0x0));
+  }
+
+  fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Frame {
+    Frame {
+      fin,
+      rsv1: false,
+      opcode,
+      payload: payload.to_vec(),
+    }
+  }
+
+  #[test]
+  fn reassembles_fragments_around_a_control_frame() {
+    let mut assembler = MessageAssembler::new(100);
+    assert!(assembler.push(frame(false, 0x1, b"hel")).unwrap().is_none());
+    let ping = assembler.push(frame(true, 0x9, b"p")).unwrap().unwrap();
+    assert_eq!(ping, (0x9, b"p".to_vec(), false));
+    assert!(assembler.push(frame(false, 0x0, b"lo ")).unwrap().is_none());
+    let message = assembler.push(frame(true, 0x0, b"there")).unwrap().unwrap();
+    assert_eq!(message, (0x1, b"hello there".to_vec(), false));
+    // and it is ready for the next message
+    let message = assembler.push(frame(true, 0x2, b"next")).unwrap().unwrap();
+    assert_eq!(message, (0x2, b"next".to_vec(), false));
+  }
+
+  #[test]
+  fn reports_compression_from_the_first_fragment() {
+    let mut assembler = MessageAssembler::new(100);
+    let first = Frame {
+      rsv1: true,
+      ..frame(false, 0x1, b"a")
+    };
+    assert!(assembler.push(first).unwrap().is_none());
+    let message = assembler.push(frame(true, 0x0, b"b")).unwrap().unwrap();
+    assert_eq!(message, (0x1, b"ab".to_vec(), true));
+    let message = assembler.push(frame(true, 0x1, b"c")).unwrap().unwrap();
+    assert_eq!(message, (0x1, b"c".to_vec(), false));
+  }
+
+  #[test]
+  fn rejects_out_of_order_fragments() {
+    let mut assembler = MessageAssembler::new(100);
+    assert!(matches!(
+      assembler.push(frame(true, 0x0, b"x")),
+      Err(FrameError::UnexpectedContinuation)
+    ));
+    assembler.push(frame(false, 0x2, b"x")).unwrap();
+    assert!(matches!(
+      assembler.push(frame(true, 0x1, b"y")),
+      Err(FrameError::IncompleteMessage)
+    ));
+  }
+
+  #[test]
+  fn limits_the_reassembled_size() {
+    let mut assembler = MessageAssembler::new(10);
+    assembler.push(frame(false, 0x1, &[b'a'; 6])).unwrap();
+    assert!(matches!(
+      assembler.push(frame(true, 0x0, &[b'a'; 5])),
+      Err(FrameError::MessageTooLarge)
+    ));
+    let mut assembler = MessageAssembler::new(10);
+    assert!(assembler.push(frame(true, 0x2, &[0; 11])).is_err());
+    assert!(assembler.push(frame(true, 0x2, &[0; 10])).is_ok());
+  }
+
+  #[test]
+  fn close_payloads() {
+    let close = unpack_close_payload(&[0x03, 0xE8, b'b', b'y', b'e']).unwrap();
+    assert_eq!((close.code, close.reason.as_str()), (1000, "bye"));
+    assert_eq!(unpack_close_payload(&[]).unwrap().code, CLOSE_NO_STATUS);
+    assert!(matches!(
+      unpack_close_payload(&[0x03]),
+      Err(FrameError::InvalidClosePayload)
+    ));
+    // 1005 is only ever reported locally, it can't be sent
+    assert!(matches!(
+      unpack_close_payload(&[0x03, 0xED]),
+      Err(FrameError::InvalidClosePayload)
+    ));
+    assert!(matches!(
+      unpack_close_payload(&[0x03, 0xE8, 0xff]),
+      Err(FrameError::InvalidUtf8)
+    ));
+  }
+
+  #[test]
+  fn packs_close_frames() {
+    assert_eq!(
+      pack_close_frame(&CloseReason::new(CLOSE_NO_STATUS, "")),
+      [0x88, 0]
+    );
+    assert_eq!(
+      pack_close_frame(&CloseReason::new(CLOSE_GOING_AWAY, "bye")),
+      [0x88, 5, 0x03, 0xE9, b'b', b'y', b'e']
+    );
+    // the reason is cut to fit a control frame without splitting a character
+    let frame = pack_close_frame(&CloseReason::new(CLOSE_GOING_AWAY, &"é".repeat(100)));
+    assert_eq!(frame[1], 124);
+    assert!(std::str::from_utf8(&frame[4..]).is_ok());
+  }
+}
@@ -0,0 +1,366 @@
+use crate::utils::utils::sec_websocket_key;
+use base64::engine::general_purpose;
+use base64::Engine;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::Instant;
+
+pub const MAX_REQUEST_SIZE: usize = 8192;
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum HandshakeError {
+  ConnectionClosed,
+  Timeout,
+  TooLarge,
+  BadRequest(&'static str),
+  UpgradeRequired,
+  UnsupportedVersion,
+  ServiceUnavailable,
+  TooManyRequests,
+}
+
+impl HandshakeError {
+  // HTTP response to send back before dropping the connection, None if nobody is listening
+  pub fn response(&self) -> Option<String> {
+    let (status, extra_headers, body) = match self {
+      HandshakeError::ConnectionClosed | HandshakeError::Timeout => return None,
+      HandshakeError::TooLarge => ("431 Request Header Fields Too Large", "", ""),
+      HandshakeError::BadRequest(reason) => ("400 Bad Request", "", *reason),
+      HandshakeError::UpgradeRequired => ("426 Upgrade Required", "Upgrade: websocket\r\n", ""),
+      HandshakeError::UnsupportedVersion => {
+        ("426 Upgrade Required", "Sec-WebSocket-Version: 13\r\n", "")
+      }
+      HandshakeError::ServiceUnavailable => ("503 Service Unavailable", "", ""),
+      HandshakeError::TooManyRequests => ("429 Too Many Requests", "", ""),
+    };
+    Some(format!(
+      "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+      status,
+      extra_headers,
+      body.len(),
+      body
+    ))
+  }
+}
+
+#[derive(Debug)]
+pub struct HandshakeRequest {
+  pub path: String,
+  // names are lowercased, repeated headers are joined with ", "
+  pub headers: HashMap<String, String>,
+}
+
+impl HandshakeRequest {
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self
+      .headers
+      .get(&name.to_ascii_lowercase())
+      .map(|value| value.as_str())
+  }
+
+  pub fn header_has_token(&self, name: &str, token: &str) -> bool {
+    match self.header(name) {
+      Some(value) => value
+        .split(',')
+        .any(|value_token| value_token.trim().eq_ignore_ascii_case(token)),
+      None => false,
+    }
+  }
+}
+
+// Reads until the blank line that ends the request headers. Anything the client sent after it
+// already belongs to the websocket stream and is returned alongside the request.
+pub async fn read_request<S: AsyncRead + Unpin>(
+  stream: &mut S,
+) -> Result<(HandshakeRequest, Vec<u8>), HandshakeError> {
+  let mut request: Vec<u8> = Vec::with_capacity(1024);
+  let mut buf = [0; 1024];
+  // one deadline for the whole request, so trickling it in a byte at a time doesn't keep the
+  // connection around
+  let deadline = Instant::now() + REQUEST_TIMEOUT;
+  let header_end = loop {
+    let size = match tokio::time::timeout_at(deadline, stream.read(&mut buf)).await {
+      Ok(Ok(0)) | Ok(Err(_)) => return Err(HandshakeError::ConnectionClosed),
+      Ok(Ok(size)) => size,
+      Err(_) => return Err(HandshakeError::Timeout),
+    };
+    // only the bytes that could complete the terminator need searching again
+    let search_start = request.len().saturating_sub(3);
+    request.extend_from_slice(&buf[..size]);
+    if let Some(pos) = request[search_start..]
+      .windows(4)
+      .position(|window| window == b"\r\n\r\n")
+    {
+      break search_start + pos + 4;
+    }
+    if request.len() > MAX_REQUEST_SIZE {
+      return Err(HandshakeError::TooLarge);
+    }
+  };
+  if header_end > MAX_REQUEST_SIZE {
+    return Err(HandshakeError::TooLarge);
+  }
+  let leftover = request.split_off(header_end);
+  Ok((parse_request(&request)?, leftover))
+}
+
+pub fn parse_request(request: &[u8]) -> Result<HandshakeRequest, HandshakeError> {
+  let request =
+    std::str::from_utf8(request).map_err(|_| HandshakeError::BadRequest("not UTF-8"))?;
+  let mut lines = request
+    .split('\n')
+    .map(|line| line.strip_suffix('\r').unwrap_or(line));
+
+  let first_line: Vec<&str> = lines.next().unwrap_or("").split(' ').collect();
+  if first_line.len() != 3 {
+    return Err(HandshakeError::BadRequest("malformed request line"));
+  }
+  if first_line[0] != "GET" {
+    return Err(HandshakeError::BadRequest("method must be GET"));
+  }
+  if !first_line[1].starts_with('/') {
+    return Err(HandshakeError::BadRequest("malformed request target"));
+  }
+  if first_line[2] != "HTTP/1.1" {
+    return Err(HandshakeError::BadRequest("HTTP version must be 1.1"));
+  }
+
+  let mut headers: HashMap<String, String> = HashMap::new();
+  for line in lines.take_while(|line| !line.is_empty()) {
+    let (name, value) = match line.split_once(':') {
+      Some((name, value)) => (name, value.trim()),
+      None => return Err(HandshakeError::BadRequest("malformed header")),
+    };
+    if name.is_empty() || name.contains(char::is_whitespace) {
+      return Err(HandshakeError::BadRequest("malformed header name"));
+    }
+    headers
+      .entry(name.to_ascii_lowercase())
+      .and_modify(|existing| {
+        existing.push_str(", ");
+        existing.push_str(value);
+      })
+      .or_insert_with(|| String::from(value));
+  }
+
+  Ok(HandshakeRequest {
+    path: String::from(first_line[1]),
+    headers,
+  })
+}
+
+// Checks the websocket specific headers and returns the Sec-WebSocket-Accept value to answer with
+pub fn accept_key(request: &HandshakeRequest) -> Result<String, HandshakeError> {
+  if request.header("Host").is_none() {
+    return Err(HandshakeError::BadRequest("missing Host header"));
+  }
+  if !request.header_has_token("Upgrade", "websocket")
+    || !request.header_has_token("Connection", "Upgrade")
+  {
+    return Err(HandshakeError::UpgradeRequired);
+  }
+  if request.header("Sec-WebSocket-Version") != Some("13") {
+    return Err(HandshakeError::UnsupportedVersion);
+  }
+  let key = match request.header("Sec-WebSocket-Key") {
+    Some(key) => key,
+    None => {
+      return Err(HandshakeError::BadRequest(
+        "missing Sec-WebSocket-Key header",
+      ))
+    }
+  };
+  // the key has to be a base64 encoded 16 byte nonce
+  match general_purpose::STANDARD.decode(key) {
+    Ok(nonce) if nonce.len() == 16 => {}
+    _ => {
+      return Err(HandshakeError::BadRequest(
+        "invalid Sec-WebSocket-Key header",
+      ))
+    }
+  }
+  Ok(sec_websocket_key(String::from(key)))
+}
+
+// Value of a parameter in the request target's query string, taken as is without percent decoding
+pub fn query_param<'a>(path: &'a str, name: &str) -> Option<&'a str> {
+  let (_, query) = path.split_once('?')?;
+  query
+    .split('&')
+    .find_map(|param| match param.split_once('=') {
+      Some((key, value)) if key == name => Some(value),
+      _ => None,
+    })
+}
+
+// The first subprotocol in the client's Sec-WebSocket-Protocol header that we support. The client
+// lists them in its order of preference, and names are compared case-sensitively.
+pub fn select_protocol(request: &HandshakeRequest, supported: &[String]) -> Option<String> {
+  request
+    .header("Sec-WebSocket-Protocol")?
+    .split(',')
+    .map(|protocol| protocol.trim())
+    .find(|protocol| supported.iter().any(|supported| supported == protocol))
+    .map(String::from)
+}
+
+pub fn switching_protocols_response(
+  accept_key: &str,
+  protocol: Option<&str>,
+  extensions: Option<&str>,
+) -> String {
+  let protocol_header = match protocol {
+    Some(protocol) => format!("Sec-WebSocket-Protocol: {}\r\n", protocol),
+    None => String::new(),
+  };
+  let extensions_header = match extensions {
+    Some(extensions) => format!("Sec-WebSocket-Extensions: {}\r\n", extensions),
+    None => String::new(),
+  };
+  format!(
+    "HTTP/1.1 101 Switching Protocols\r\n\
+    Upgrade: websocket\r\n\
+    Connection: Upgrade\r\n\
+    Sec-WebSocket-Accept: {}\r\n\
+    {}{}\r\n",
+    accept_key, protocol_header, extensions_header
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const REQUEST: &str = "GET /chat HTTP/1.1\r\n\
+    Host: example.com\r\n\
+    Upgrade: websocket\r\n\
+    Connection: keep-alive, Upgrade\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+    Sec-WebSocket-Version: 13\r\n\
+    Sec-WebSocket-Protocol: chat, v2\r\n\
+    X-Repeated: one\r\n\
+    x-repeated: two\r\n\
+    \r\n";
+
+  fn bad_request(request: &str) -> &'static str {
+    match parse_request(request.as_bytes()).and_then(|request| accept_key(&request)) {
+      Err(HandshakeError::BadRequest(reason)) => reason,
+      other => panic!("expected a bad request, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn parses_a_valid_request() {
+    let request = parse_request(REQUEST.as_bytes()).unwrap();
+    assert_eq!(request.path, "/chat");
+    assert_eq!(request.header("HOST"), Some("example.com"));
+    assert!(request.header_has_token("Connection", "upgrade"));
+    assert_eq!(request.header("X-Repeated"), Some("one, two"));
+    // the example from RFC 6455
+    assert_eq!(
+      accept_key(&request).unwrap(),
+      "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+  }
+
+  #[test]
+  fn rejects_a_bad_request_line() {
+    assert_eq!(
+      bad_request(&REQUEST.replacen("GET", "POST", 1)),
+      "method must be GET"
+    );
+    assert_eq!(
+      bad_request(&REQUEST.replacen("HTTP/1.1", "HTTP/1.0", 1)),
+      "HTTP version must be 1.1"
+    );
+    assert_eq!(
+      bad_request(&REQUEST.replacen("/chat", "chat", 1)),
+      "malformed request target"
+    );
+    assert_eq!(
+      bad_request("GET / HTTP/1.1 extra\r\n\r\n"),
+      "malformed request line"
+    );
+  }
+
+  #[test]
+  fn rejects_bad_headers() {
+    assert_eq!(
+      bad_request(&REQUEST.replacen("Host: example.com\r\n", "", 1)),
+      "missing Host header"
+    );
+    assert_eq!(
+      bad_request(&REQUEST.replacen("Host: example.com", "Host example.com", 1)),
+      "malformed header"
+    );
+    assert_eq!(
+      bad_request(&REQUEST.replacen("dGhlIHNhbXBsZSBub25jZQ==", "c2hvcnQ=", 1)),
+      "invalid Sec-WebSocket-Key header"
+    );
+  }
+
+  #[test]
+  fn rejects_a_request_that_is_not_an_upgrade() {
+    let request = parse_request(REQUEST.replacen("websocket", "h2c", 1).as_bytes()).unwrap();
+    assert!(matches!(
+      accept_key(&request),
+      Err(HandshakeError::UpgradeRequired)
+    ));
+    let request =
+      parse_request(REQUEST.replacen("Version: 13", "Version: 8", 1).as_bytes()).unwrap();
+    let err = accept_key(&request).unwrap_err();
+    assert!(matches!(err, HandshakeError::UnsupportedVersion));
+    assert!(err
+      .response()
+      .unwrap()
+      .contains("Sec-WebSocket-Version: 13\r\n"));
+  }
+
+  #[test]
+  fn selects_the_clients_first_supported_protocol() {
+    let request = parse_request(REQUEST.as_bytes()).unwrap();
+    let supported = |names: &[&str]| {
+      names
+        .iter()
+        .map(|name| String::from(*name))
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(
+      select_protocol(&request, &supported(&["v2", "chat"])).as_deref(),
+      Some("chat")
+    );
+    assert_eq!(
+      select_protocol(&request, &supported(&["v2"])).as_deref(),
+      Some("v2")
+    );
+    assert_eq!(select_protocol(&request, &supported(&["Chat"])), None);
+    assert_eq!(select_protocol(&request, &[]), None);
+  }
+
+  #[test]
+  fn answers_with_the_agreed_headers() {
+    let response = switching_protocols_response("key", None, None);
+    assert_eq!(
+      response,
+      "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+      Sec-WebSocket-Accept: key\r\n\r\n"
+    );
+    let response = switching_protocols_response("key", Some("chat"), Some("permessage-deflate"));
+    assert!(response.ends_with(
+      "Sec-WebSocket-Accept: key\r\nSec-WebSocket-Protocol: chat\r\n\
+      Sec-WebSocket-Extensions: permessage-deflate\r\n\r\n"
+    ));
+  }
+
+  #[test]
+  fn reads_query_parameters() {
+    assert_eq!(query_param("/?token=abc.1.x", "token"), Some("abc.1.x"));
+    assert_eq!(query_param("/chat?v=2&token=abc", "token"), Some("abc"));
+    assert_eq!(query_param("/chat?v=2&token=", "token"), Some(""));
+    assert_eq!(query_param("/chat?v=2", "token"), None);
+    assert_eq!(query_param("/chat?xtoken=abc", "token"), None);
+    assert_eq!(query_param("/token=abc", "token"), None);
+  }
+}
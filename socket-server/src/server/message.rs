@@ -0,0 +1,553 @@
+use crate::server::frame::Payload;
+use crate::server::rooms::Presence;
+use crate::server::routing::Route;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+// Subprotocol that switches a connection to JSON envelopes
+pub const JSON_PROTOCOL: &str = "json";
+const DEFAULT_TYPE: &str = "message";
+const SUBSCRIBE_TYPE: &str = "subscribe";
+const UNSUBSCRIBE_TYPE: &str = "unsubscribe";
+const JOIN_TYPE: &str = "join";
+const LEAVE_TYPE: &str = "leave";
+const ACK_TYPE: &str = "ack";
+const READ_TYPE: &str = "read";
+
+// How a connection's text messages are laid out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageFormat {
+  // "id1,id2,...,message"
+  Legacy,
+  // {"to":[...] or "topic":... or "room":... or "broadcast":true,"type":...,"body":...,"id":...}
+  Json,
+}
+
+impl MessageFormat {
+  pub fn from_name(name: &str) -> Option<MessageFormat> {
+    match name {
+      "legacy" => Some(MessageFormat::Legacy),
+      "json" => Some(MessageFormat::Json),
+      _ => None,
+    }
+  }
+}
+
+fn default_type() -> String {
+  String::from(DEFAULT_TYPE)
+}
+
+// What a JSON client sends. A message needs a body and exactly one of "to", "topic", "room" or
+// "broadcast". The "subscribe" and "unsubscribe" types only need a topic, "join" and "leave" only
+// need a room. "id" is the sender's own reference and is passed along untouched. The "ack" and
+// "read" receipts need "id" set to the message's id, and go back to whoever sent the client a
+// message with that id. "to" narrows that down when several senders used the same id.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Envelope {
+  #[serde(default)]
+  pub to: Option<Vec<u32>>,
+  #[serde(default)]
+  pub topic: Option<String>,
+  #[serde(default)]
+  pub room: Option<String>,
+  #[serde(default)]
+  pub broadcast: bool,
+  #[serde(rename = "type", default = "default_type")]
+  pub kind: String,
+  #[serde(default)]
+  pub body: Option<Value>,
+  #[serde(default)]
+  pub id: Option<Value>,
+}
+
+// What a JSON client asked the server to do
+#[derive(Debug)]
+pub enum Request {
+  Send(Route, Outgoing),
+  Subscribe {
+    topic: String,
+    id: Option<Value>,
+  },
+  Unsubscribe {
+    topic: String,
+    id: Option<Value>,
+  },
+  Join {
+    room: String,
+    id: Option<Value>,
+  },
+  Leave {
+    room: String,
+    id: Option<Value>,
+  },
+  // the server works out who it goes to, see Receipts
+  Receipt {
+    to: Option<Vec<u32>>,
+    kind: String,
+    id: Value,
+  },
+}
+
+impl Envelope {
+  pub fn into_request(self, from: u32) -> Result<Request, EnvelopeError> {
+    let Envelope {
+      to,
+      topic,
+      room,
+      broadcast,
+      kind,
+      body,
+      id,
+    } = self;
+    let topic = topic.filter(|topic| !topic.is_empty());
+    let room = room.filter(|room| !room.is_empty());
+    match kind.as_str() {
+      SUBSCRIBE_TYPE | UNSUBSCRIBE_TYPE => {
+        return match topic {
+          Some(topic) if kind == SUBSCRIBE_TYPE => Ok(Request::Subscribe { topic, id }),
+          Some(topic) => Ok(Request::Unsubscribe { topic, id }),
+          None => Err(EnvelopeError {
+            reason: format!("{} needs a topic", kind),
+            id,
+          }),
+        };
+      }
+      JOIN_TYPE | LEAVE_TYPE => {
+        return match room {
+          Some(room) if kind == JOIN_TYPE => Ok(Request::Join { room, id }),
+          Some(room) => Ok(Request::Leave { room, id }),
+          None => Err(EnvelopeError {
+            reason: format!("{} needs a room", kind),
+            id,
+          }),
+        };
+      }
+      ACK_TYPE | READ_TYPE => {
+        return match id {
+          Some(id) => Ok(Request::Receipt { to, kind, id }),
+          None => Err(EnvelopeError {
+            reason: format!("{} needs the \"id\" of the message it answers", kind),
+            id,
+          }),
+        };
+      }
+      _ => {}
+    }
+    let route = match (to, topic.clone(), room.clone(), broadcast) {
+      (Some(to), None, None, false) => Route::Clients(to),
+      (None, Some(topic), None, false) => Route::Topic(topic),
+      (None, None, Some(room), false) => Route::Room(room),
+      (None, None, None, true) => Route::Broadcast,
+      _ => {
+        return Err(EnvelopeError {
+          reason: String::from("a message needs exactly one of to, topic, room or broadcast"),
+          id,
+        })
+      }
+    };
+    match body {
+      Some(body) => Ok(Request::Send(
+        route,
+        Outgoing::Envelope {
+          from,
+          kind,
+          topic,
+          room,
+          body,
+          id,
+        },
+      )),
+      None => Err(EnvelopeError {
+        reason: String::from("a message needs a body"),
+        id,
+      }),
+    }
+  }
+}
+
+// Something only the sender sees, like {"type":"subscribed","topic":...,"id":...}. The fields
+// have to be a JSON object.
+pub fn reply(kind: &str, fields: Value, id: &Option<Value>) -> Payload {
+  let mut reply = match fields {
+    Value::Object(fields) => fields,
+    _ => Map::new(),
+  };
+  reply.insert(String::from("type"), json!(kind));
+  if let Some(id) = id {
+    reply.insert(String::from("id"), id.clone());
+  }
+  Payload::Text(Value::Object(reply).to_string())
+}
+
+#[derive(Debug)]
+pub struct EnvelopeError {
+  pub reason: String,
+  // the sender's message id, when it could be read
+  pub id: Option<Value>,
+}
+
+impl EnvelopeError {
+  // Text reply telling the sender what was wrong with its message
+  pub fn reply(&self) -> Payload {
+    reply("error", json!({ "error": self.reason }), &self.id)
+  }
+}
+
+pub fn parse_envelope(text: &str) -> Result<Envelope, EnvelopeError> {
+  let value: Value = serde_json::from_str(text).map_err(|err| EnvelopeError {
+    reason: format!("invalid JSON: {}", err),
+    id: None,
+  })?;
+  // picked out first so even a bad envelope gets its id echoed back
+  let id = value.get("id").cloned();
+  if !value.is_object() {
+    return Err(EnvelopeError {
+      reason: String::from("envelope must be an object"),
+      id,
+    });
+  }
+  serde_json::from_value(value).map_err(|err| EnvelopeError {
+    reason: format!("invalid envelope: {}", err),
+    id,
+  })
+}
+
+// Splits "id1,id2,...,message" into the recipients and the message. Leading segments that are
+// client ids are recipients and everything after them is the message, commas and all. The last
+// segment always belongs to the message.
+pub fn parse_legacy(text: &str) -> (Vec<u32>, String) {
+  let segments: Vec<&str> = text.split(',').collect();
+  let ids: Vec<u32> = segments[..segments.len() - 1]
+    .iter()
+    .map_while(|segment| segment.parse::<u32>().ok())
+    .collect();
+  let message = segments[ids.len()..].join(",");
+  (ids, message)
+}
+
+// A message on its way to its recipients, rendered for each one in its own format
+#[derive(Debug, Clone)]
+pub enum Outgoing {
+  // from a legacy client, or any binary message
+  Raw {
+    from: u32,
+    payload: Payload,
+  },
+  Envelope {
+    from: u32,
+    kind: String,
+    // set when it was published to a topic or sent to a room
+    topic: Option<String>,
+    room: Option<String>,
+    body: Value,
+    id: Option<Value>,
+  },
+  // a recipient telling the sender it got or read a message
+  Receipt {
+    from: u32,
+    kind: String,
+    id: Value,
+  },
+  // a room member joining, leaving or timing out, only JSON clients can be in rooms
+  Presence {
+    client: u32,
+    room: String,
+    event: Presence,
+  },
+}
+
+impl Outgoing {
+  // The sender's id for its message, a delivery report goes back to it when there is one
+  pub fn report_id(&self) -> Option<&Value> {
+    match self {
+      Outgoing::Envelope { id, .. } => id.as_ref(),
+      _ => None,
+    }
+  }
+
+  pub fn render(&self, format: MessageFormat) -> Payload {
+    match (self, format) {
+      (Outgoing::Raw { payload, .. }, MessageFormat::Legacy) => payload.clone(),
+      // binary messages have no envelope, JSON clients get them as they are
+      (Outgoing::Raw { payload, .. }, MessageFormat::Json) if payload.opcode() == 0x2 => {
+        payload.clone()
+      }
+      (Outgoing::Raw { from, payload }, MessageFormat::Json) => Payload::Text(
+        json!({
+          "from": from,
+          "type": DEFAULT_TYPE,
+          "body": String::from_utf8_lossy(payload.as_bytes()),
+        })
+        .to_string(),
+      ),
+      // legacy clients only ever got the message itself
+      (Outgoing::Envelope { body, .. }, MessageFormat::Legacy) => match body {
+        Value::String(text) => Payload::Text(text.clone()),
+        body => Payload::Text(body.to_string()),
+      },
+      (
+        Outgoing::Envelope {
+          from,
+          kind,
+          topic,
+          room,
+          body,
+          id,
+        },
+        MessageFormat::Json,
+      ) => {
+        let mut message = Map::new();
+        message.insert(String::from("from"), json!(from));
+        message.insert(String::from("type"), json!(kind));
+        if let Some(topic) = topic {
+          message.insert(String::from("topic"), json!(topic));
+        }
+        if let Some(room) = room {
+          message.insert(String::from("room"), json!(room));
+        }
+        message.insert(String::from("body"), body.clone());
+        if let Some(id) = id {
+          message.insert(String::from("id"), id.clone());
+        }
+        Payload::Text(Value::Object(message).to_string())
+      }
+      // only JSON clients send ids, so only they get receipts
+      (Outgoing::Receipt { from, kind, id }, _) => Payload::Text(
+        json!({
+          "from": from,
+          "type": kind,
+          "id": id,
+        })
+        .to_string(),
+      ),
+      (
+        Outgoing::Presence {
+          client,
+          room,
+          event,
+        },
+        _,
+      ) => Payload::Text(
+        json!({
+          "type": "presence",
+          "event": event.name(),
+          "room": room,
+          "client": client,
+        })
+        .to_string(),
+      ),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn splits_recipients_from_the_message() {
+    assert_eq!(
+      parse_legacy("1,2,3,hello"),
+      (vec![1, 2, 3], String::from("hello"))
+    );
+    assert_eq!(parse_legacy("7,"), (vec![7], String::new()));
+  }
+
+  #[test]
+  fn keeps_commas_in_the_message() {
+    assert_eq!(
+      parse_legacy("1,2,hello, world,3"),
+      (vec![1, 2], String::from("hello, world,3"))
+    );
+  }
+
+  #[test]
+  fn reads_a_message_without_recipients() {
+    assert_eq!(parse_legacy("hello"), (vec![], String::from("hello")));
+    assert_eq!(parse_legacy(""), (vec![], String::new()));
+    assert_eq!(
+      parse_legacy("x,1,hello"),
+      (vec![], String::from("x,1,hello"))
+    );
+  }
+
+  #[test]
+  fn the_last_segment_is_always_the_message() {
+    assert_eq!(parse_legacy("42"), (vec![], String::from("42")));
+    assert_eq!(parse_legacy("1,42"), (vec![1], String::from("42")));
+  }
+
+  fn json(payload: Payload) -> Value {
+    serde_json::from_str(&payload.into_text().unwrap()).unwrap()
+  }
+
+  #[test]
+  fn parses_an_envelope() {
+    let envelope = parse_envelope(r#"{"to":[1,2],"body":{"text":"hi"},"id":7}"#).unwrap();
+    assert_eq!(envelope.to, Some(vec![1, 2]));
+    assert_eq!(envelope.kind, DEFAULT_TYPE);
+    assert_eq!(envelope.body, Some(json!({"text": "hi"})));
+    assert_eq!(envelope.id, Some(json!(7)));
+  }
+
+  #[test]
+  fn echoes_the_id_of_a_bad_envelope() {
+    let err = parse_envelope(r#"{"to":"1","body":"hi","id":"a1"}"#).unwrap_err();
+    assert_eq!(err.id, Some(json!("a1")));
+    let reply = json(err.reply());
+    assert_eq!(
+      (&reply["type"], &reply["id"]),
+      (&json!("error"), &json!("a1"))
+    );
+    assert!(reply["error"]
+      .as_str()
+      .unwrap()
+      .starts_with("invalid envelope"));
+
+    let err = parse_envelope(r#"{"to":[1],"body":"hi","colour":"red"}"#).unwrap_err();
+    assert!(err.reason.contains("colour"));
+    let err = parse_envelope("[1,2]").unwrap_err();
+    assert_eq!(err.reason, "envelope must be an object");
+    let err = parse_envelope("{").unwrap_err();
+    assert!(err.reason.starts_with("invalid JSON"));
+    assert!(json(err.reply()).get("id").is_none());
+  }
+
+  #[test]
+  fn renders_for_each_format() {
+    let envelope = Outgoing::Envelope {
+      from: 3,
+      kind: String::from("note"),
+      topic: None,
+      room: None,
+      body: json!("hi"),
+      id: Some(json!(9)),
+    };
+    assert_eq!(
+      envelope.render(MessageFormat::Legacy).into_text().unwrap(),
+      "hi"
+    );
+    assert_eq!(
+      json(envelope.render(MessageFormat::Json)),
+      json!({"from": 3, "type": "note", "body": "hi", "id": 9})
+    );
+
+    let raw = Outgoing::Raw {
+      from: 4,
+      payload: Payload::Text(String::from("1,hello")),
+    };
+    assert_eq!(
+      raw.render(MessageFormat::Legacy).into_text().unwrap(),
+      "1,hello"
+    );
+    assert_eq!(
+      json(raw.render(MessageFormat::Json)),
+      json!({"from": 4, "type": "message", "body": "1,hello"})
+    );
+    let binary = Outgoing::Raw {
+      from: 4,
+      payload: Payload::Binary(vec![1, 2]),
+    };
+    assert_eq!(binary.render(MessageFormat::Json).opcode(), 0x2);
+  }
+
+  fn request(text: &str) -> Result<Request, EnvelopeError> {
+    parse_envelope(text).unwrap().into_request(5)
+  }
+
+  #[test]
+  fn routes_each_kind_of_envelope() {
+    assert!(matches!(
+      request(r#"{"to":[1,2],"body":"x"}"#),
+      Ok(Request::Send(Route::Clients(to), _)) if to == [1, 2]
+    ));
+    assert!(matches!(
+      request(r#"{"broadcast":true,"body":"x"}"#),
+      Ok(Request::Send(Route::Broadcast, _))
+    ));
+    match request(r#"{"topic":"news","body":"x","id":1}"#) {
+      Ok(Request::Send(Route::Topic(topic), message)) => {
+        assert_eq!(topic, "news");
+        assert_eq!(
+          json(message.render(MessageFormat::Json)),
+          json!({"from": 5, "type": "message", "topic": "news", "body": "x", "id": 1})
+        );
+      }
+      other => panic!("{:?}", other),
+    }
+    assert!(matches!(
+      request(r#"{"type":"subscribe","topic":"news"}"#),
+      Ok(Request::Subscribe { topic, .. }) if topic == "news"
+    ));
+    assert!(matches!(
+      request(r#"{"type":"unsubscribe","topic":"news","id":2}"#),
+      Ok(Request::Unsubscribe { topic, id: Some(_) }) if topic == "news"
+    ));
+    assert!(matches!(
+      request(r#"{"room":"lobby","body":"x"}"#),
+      Ok(Request::Send(Route::Room(room), _)) if room == "lobby"
+    ));
+    assert!(matches!(
+      request(r#"{"type":"join","room":"lobby"}"#),
+      Ok(Request::Join { room, .. }) if room == "lobby"
+    ));
+    assert!(matches!(
+      request(r#"{"type":"leave","room":"lobby"}"#),
+      Ok(Request::Leave { room, .. }) if room == "lobby"
+    ));
+    assert!(matches!(
+      request(r#"{"type":"read","id":"m1"}"#),
+      Ok(Request::Receipt { to: None, kind, id }) if kind == "read" && id == "m1"
+    ));
+    assert!(matches!(
+      request(r#"{"type":"ack","to":[1],"id":"m1"}"#),
+      Ok(Request::Receipt { to: Some(to), .. }) if to == [1]
+    ));
+  }
+
+  #[test]
+  fn rejects_envelopes_without_a_single_route() {
+    for (text, reason) in [
+      (
+        r#"{"type":"ack","to":[1]}"#,
+        "ack needs the \"id\" of the message it answers",
+      ),
+      (
+        r#"{"to":[1],"topic":"news","body":"x"}"#,
+        "a message needs exactly one of to, topic, room or broadcast",
+      ),
+      (
+        r#"{"room":"lobby","broadcast":true,"body":"x"}"#,
+        "a message needs exactly one of to, topic, room or broadcast",
+      ),
+      (
+        r#"{"topic":"","body":"x"}"#,
+        "a message needs exactly one of to, topic, room or broadcast",
+      ),
+      (r#"{"to":[1]}"#, "a message needs a body"),
+      (
+        r#"{"type":"subscribe","topic":""}"#,
+        "subscribe needs a topic",
+      ),
+      (r#"{"type":"join"}"#, "join needs a room"),
+    ] {
+      match request(text) {
+        Err(err) => assert_eq!(err.reason, reason),
+        other => panic!("{} gave {:?}", text, other),
+      }
+    }
+  }
+
+  #[test]
+  fn renders_presence_the_same_for_everyone() {
+    let presence = Outgoing::Presence {
+      client: 7,
+      room: String::from("lobby"),
+      event: Presence::Timeout,
+    };
+    let expected = json!({"type": "presence", "event": "timeout", "room": "lobby", "client": 7});
+    assert_eq!(json(presence.render(MessageFormat::Json)), expected);
+    assert_eq!(json(presence.render(MessageFormat::Legacy)), expected);
+  }
+}
@@ -1,5 +1,17 @@
+pub mod auth;
 pub mod concurrent;
 pub mod connectedclient;
+pub mod deflate;
+pub mod frame;
+pub mod handshake;
+pub mod message;
+pub mod offline;
+pub mod outbound;
+pub mod ratelimit;
+pub mod receipts;
+pub mod rooms;
+pub mod routing;
 pub mod server;
+pub mod tls;
 
 pub use server::*;
@@ -0,0 +1,135 @@
+use crate::server::message::Outgoing;
+use crate::utils::config::OfflineConfig;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+pub type OfflineMap = Arc<Mutex<OfflineQueues>>;
+
+struct Queue {
+  // when the client's last session closed
+  since: Instant,
+  messages: VecDeque<Outgoing>,
+}
+
+// Messages for clients that disconnected a moment ago, handed over when they register again
+pub struct OfflineQueues {
+  enabled: bool,
+  max_messages: usize,
+  ttl: Duration,
+  queues: HashMap<u32, Queue>,
+}
+
+impl OfflineQueues {
+  pub fn new(config: &OfflineConfig) -> OfflineQueues {
+    OfflineQueues {
+      enabled: *config.enabled(),
+      max_messages: *config.max_messages(),
+      ttl: config.ttl(),
+      queues: HashMap::new(),
+    }
+  }
+
+  // Starts keeping messages for a client whose last session just closed
+  pub fn track(&mut self, id: u32) {
+    if !self.enabled {
+      return;
+    }
+    // a good moment to forget clients that never came back
+    let ttl = self.ttl;
+    self.queues.retain(|_, queue| queue.since.elapsed() < ttl);
+    self.queues.insert(
+      id,
+      Queue {
+        since: Instant::now(),
+        messages: VecDeque::new(),
+      },
+    );
+  }
+
+  // Keeps a message for a disconnected client, false if the client isn't being waited for
+  pub fn push(&mut self, id: u32, message: &Outgoing) -> bool {
+    let queue = match self.queues.get_mut(&id) {
+      Some(queue) if queue.since.elapsed() < self.ttl => queue,
+      Some(_) => {
+        debug!("Client {} has been gone too long, dropping its queue", id);
+        self.queues.remove(&id);
+        return false;
+      }
+      None => return false,
+    };
+    if queue.messages.len() >= self.max_messages {
+      warn!(
+        "Offline queue for client {} is full, dropping its oldest message",
+        id
+      );
+      queue.messages.pop_front();
+    }
+    queue.messages.push_back(message.clone());
+    true
+  }
+
+  // Hands over what was kept for a client that registered again, oldest first
+  pub fn take(&mut self, id: u32) -> Vec<Outgoing> {
+    match self.queues.remove(&id) {
+      Some(queue) if queue.since.elapsed() < self.ttl => queue.messages.into(),
+      _ => Vec::new(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::{json, Value};
+
+  fn queues(toml: &str) -> OfflineQueues {
+    OfflineQueues::new(&toml::from_str(toml).unwrap())
+  }
+
+  fn message(n: u32) -> Outgoing {
+    Outgoing::Envelope {
+      from: 1,
+      kind: String::from("message"),
+      topic: None,
+      room: None,
+      body: json!(n),
+      id: None,
+    }
+  }
+
+  fn bodies(messages: Vec<Outgoing>) -> Vec<Value> {
+    messages
+      .into_iter()
+      .map(|message| match message {
+        Outgoing::Envelope { body, .. } => body,
+        other => panic!("unexpected {:?}", other),
+      })
+      .collect()
+  }
+
+  #[test]
+  fn keeps_messages_for_tracked_clients() {
+    let mut queues = queues("enabled = true\nmax_messages = 2");
+    assert!(!queues.push(7, &message(0)));
+    queues.track(7);
+    for n in 1..=3 {
+      assert!(queues.push(7, &message(n)));
+    }
+    // the oldest message made room for the newest
+    assert_eq!(bodies(queues.take(7)), [json!(2), json!(3)]);
+    assert!(queues.take(7).is_empty());
+    assert!(!queues.push(7, &message(4)));
+  }
+
+  #[test]
+  fn keeps_nothing_when_disabled() {
+    let mut queues = queues("enabled = false");
+    queues.track(7);
+    assert!(!queues.push(7, &message(1)));
+    assert!(queues.take(7).is_empty());
+  }
+}
@@ -0,0 +1,311 @@
+use crate::server::connectedclient::ClientWriter;
+use crate::server::deflate::Deflater;
+use crate::server::frame::{pack_compressed_frame, pack_message_frame, Payload};
+use crate::utils::config::OverflowPolicy;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+use tracing::debug;
+
+// What happened to a frame handed to the queue
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Push {
+  Queued,
+  // the queue was full and the frame was dropped
+  Full,
+  // the queue was full, its backlog was dropped and the client should be closed
+  Disconnect,
+  // the writer has stopped, nothing reaches the client any more
+  Closed,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutboundStats {
+  // frames waiting to be written
+  pub depth: usize,
+  // the most that were ever waiting at once
+  pub peak: usize,
+  // messages thrown away because the queue was full
+  pub dropped: u64,
+}
+
+// Messages are only framed when they are written. With context takeover every compressed message
+// depends on the ones before it, so compressing one that ends up dropped would leave the client
+// unable to read the rest.
+enum Entry {
+  Message(Payload),
+  // already framed, and never dropped to make room
+  Control(Vec<u8>),
+}
+
+struct State {
+  frames: VecDeque<Entry>,
+  // how many of the frames are messages, only those count towards the capacity
+  messages: usize,
+  // set by close, the writer finishes what is queued and shuts the stream down
+  closing: bool,
+  // set once the writer has stopped
+  closed: bool,
+  // set when the client fell too far behind, only control frames are queued from then on
+  discarding: bool,
+  stats: OutboundStats,
+}
+
+// Frames waiting to go out to one connection, written by its own task so a slow client only
+// holds itself up
+pub struct Outbound {
+  state: Mutex<State>,
+  // wakes the writer when there is something to do
+  ready: Notify,
+  // wakes the connection's task once the writer has stopped
+  stopped: Notify,
+  capacity: usize,
+  overflow: OverflowPolicy,
+}
+
+impl Outbound {
+  pub fn new(capacity: usize, overflow: OverflowPolicy) -> Outbound {
+    Outbound {
+      state: Mutex::new(State {
+        frames: VecDeque::new(),
+        messages: 0,
+        closing: false,
+        closed: false,
+        discarding: false,
+        stats: OutboundStats::default(),
+      }),
+      ready: Notify::new(),
+      stopped: Notify::new(),
+      capacity,
+      overflow,
+    }
+  }
+
+  // Queues a message, what happens when the queue is full is up to the overflow policy
+  pub fn push(&self, message: Payload) -> Push {
+    let mut state = self.state.lock().unwrap();
+    if state.closed {
+      return Push::Closed;
+    }
+    if state.discarding {
+      state.stats.dropped += 1;
+      return Push::Full;
+    }
+    let result = if state.messages < self.capacity {
+      Push::Queued
+    } else {
+      match self.overflow {
+        OverflowPolicy::DropOldest => {
+          debug!("Outbound queue is full, dropping its oldest message");
+          if let Some(oldest) = state
+            .frames
+            .iter()
+            .position(|entry| matches!(entry, Entry::Message(_)))
+          {
+            state.frames.remove(oldest);
+            state.messages -= 1;
+          }
+          state.stats.dropped += 1;
+          Push::Queued
+        }
+        OverflowPolicy::DropNewest => {
+          state.stats.dropped += 1;
+          return Push::Full;
+        }
+        OverflowPolicy::Disconnect => {
+          state.stats.dropped += state.messages as u64 + 1;
+          state
+            .frames
+            .retain(|entry| matches!(entry, Entry::Control(_)));
+          state.messages = 0;
+          state.discarding = true;
+          return Push::Disconnect;
+        }
+      }
+    };
+    Self::enqueue(&mut state, Entry::Message(message));
+    self.ready.notify_one();
+    result
+  }
+
+  // Control frames skip the limit, they are small and pings and closes can't be dropped
+  pub fn push_control(&self, frame: Vec<u8>) -> Push {
+    let mut state = self.state.lock().unwrap();
+    if state.closed {
+      return Push::Closed;
+    }
+    Self::enqueue(&mut state, Entry::Control(frame));
+    self.ready.notify_one();
+    Push::Queued
+  }
+
+  // Lets the writer finish what is queued and shut the stream down
+  pub fn close(&self) {
+    self.state.lock().unwrap().closing = true;
+    self.ready.notify_one();
+  }
+
+  pub fn stats(&self) -> OutboundStats {
+    let state = self.state.lock().unwrap();
+    OutboundStats {
+      depth: state.frames.len(),
+      ..state.stats
+    }
+  }
+
+  // Resolves once the writer has stopped, because close was called or a write failed
+  pub async fn stopped(&self) {
+    loop {
+      let stopped = self.stopped.notified();
+      if self.state.lock().unwrap().closed {
+        return;
+      }
+      stopped.await;
+    }
+  }
+
+  // Writes queued frames until close is called or the client can't be written to. Messages are
+  // compressed here, in the order they go out, when the client negotiated permessage-deflate.
+  pub async fn run(&self, mut stream: ClientWriter, mut deflater: Option<Deflater>) {
+    while let Some(entry) = self.next_frame().await {
+      let frame = match entry {
+        Entry::Message(message) => match &mut deflater {
+          Some(deflater) => {
+            pack_compressed_frame(message.opcode(), &deflater.compress(message.as_bytes()))
+          }
+          None => pack_message_frame(&message),
+        },
+        Entry::Control(frame) => frame,
+      };
+      if let Err(err) = stream.write_all(&frame).await {
+        debug!("Writing to client failed: {}", err);
+        break;
+      }
+    }
+    self.state.lock().unwrap().closed = true;
+    self.stopped.notify_waiters();
+    let _ = stream.shutdown().await;
+  }
+
+  async fn next_frame(&self) -> Option<Entry> {
+    loop {
+      {
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.frames.pop_front() {
+          if let Entry::Message(_) = entry {
+            state.messages -= 1;
+          }
+          return Some(entry);
+        }
+        if state.closing {
+          return None;
+        }
+      }
+      // notify_one keeps a permit when nobody is waiting, so a push between the check and
+      // here isn't missed
+      self.ready.notified().await;
+    }
+  }
+
+  fn enqueue(state: &mut State, entry: Entry) {
+    if let Entry::Message(_) = entry {
+      state.messages += 1;
+    }
+    state.frames.push_back(entry);
+    state.stats.peak = state.stats.peak.max(state.frames.len());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn message(byte: u8) -> Payload {
+    Payload::Binary(vec![byte])
+  }
+
+  fn full_queue(overflow: OverflowPolicy) -> Outbound {
+    let outbound = Outbound::new(2, overflow);
+    assert_eq!(outbound.push(message(1)), Push::Queued);
+    assert_eq!(outbound.push(message(2)), Push::Queued);
+    outbound
+  }
+
+  async fn drain(outbound: &Outbound) -> Vec<Vec<u8>> {
+    outbound.close();
+    let mut frames = Vec::new();
+    while let Some(entry) = outbound.next_frame().await {
+      frames.push(match entry {
+        Entry::Message(message) => message.as_bytes().to_vec(),
+        Entry::Control(frame) => frame,
+      });
+    }
+    frames
+  }
+
+  #[tokio::test]
+  async fn drop_oldest_makes_room() {
+    let outbound = full_queue(OverflowPolicy::DropOldest);
+    assert_eq!(outbound.push(message(3)), Push::Queued);
+    let stats = outbound.stats();
+    assert_eq!((stats.depth, stats.peak, stats.dropped), (2, 2, 1));
+    assert_eq!(drain(&outbound).await, [vec![2], vec![3]]);
+  }
+
+  #[tokio::test]
+  async fn drop_newest_refuses_the_message() {
+    let outbound = full_queue(OverflowPolicy::DropNewest);
+    assert_eq!(outbound.push(message(3)), Push::Full);
+    assert_eq!(outbound.stats().dropped, 1);
+    assert_eq!(drain(&outbound).await, [vec![1], vec![2]]);
+  }
+
+  #[tokio::test]
+  async fn disconnect_drops_the_backlog_but_not_control_frames() {
+    let outbound = full_queue(OverflowPolicy::Disconnect);
+    assert_eq!(outbound.push_control(vec![0x89]), Push::Queued);
+    assert_eq!(outbound.push(message(3)), Push::Disconnect);
+    assert_eq!(outbound.push(message(4)), Push::Full);
+    assert_eq!(outbound.push_control(vec![0x88]), Push::Queued);
+    let stats = outbound.stats();
+    assert_eq!((stats.depth, stats.dropped), (2, 4));
+    assert_eq!(drain(&outbound).await, [vec![0x89], vec![0x88]]);
+  }
+
+  #[tokio::test]
+  async fn control_frames_skip_the_limit_and_are_never_dropped() {
+    let outbound = Outbound::new(2, OverflowPolicy::DropOldest);
+    assert_eq!(outbound.push_control(vec![0x89]), Push::Queued);
+    assert_eq!(outbound.push(message(1)), Push::Queued);
+    assert_eq!(outbound.push(message(2)), Push::Queued);
+    assert_eq!(outbound.push(message(3)), Push::Queued);
+    assert_eq!(outbound.stats().peak, 3);
+    assert_eq!(drain(&outbound).await, [vec![0x89], vec![2], vec![3]]);
+  }
+
+  #[tokio::test]
+  async fn written_messages_free_up_room() {
+    let outbound = full_queue(OverflowPolicy::DropNewest);
+    assert_eq!(outbound.push_control(vec![0x89]), Push::Queued);
+    assert!(matches!(
+      outbound.next_frame().await,
+      Some(Entry::Message(_))
+    ));
+    assert_eq!(outbound.push(message(3)), Push::Queued);
+    assert_eq!(outbound.push(message(4)), Push::Full);
+    assert_eq!(outbound.state.lock().unwrap().messages, 2);
+    assert_eq!(drain(&outbound).await, [vec![2], vec![0x89], vec![3]]);
+    assert_eq!(outbound.state.lock().unwrap().messages, 0);
+  }
+
+  #[tokio::test]
+  async fn nothing_is_queued_once_the_writer_stopped() {
+    let outbound = full_queue(OverflowPolicy::DropOldest);
+    outbound.state.lock().unwrap().closed = true;
+    assert_eq!(outbound.push(message(3)), Push::Closed);
+    assert_eq!(outbound.push_control(vec![0x88]), Push::Closed);
+    // returns straight away
+    outbound.stopped().await;
+  }
+}
@@ -0,0 +1,348 @@
+use crate::utils::config::{RateLimitAction, RateLimitConfig};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+pub type RateMap = Arc<Mutex<RateLimits>>;
+
+// how often buckets that filled up again are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// What to do with a message from a client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+  Allow,
+  Drop,
+  // let it through, then stop reading from the client for this long
+  Delay(Duration),
+  Close,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimitStats {
+  // messages that went over a client's rate, whatever was done with them
+  pub limited: u64,
+  pub dropped: u64,
+  pub delayed: u64,
+  // clients closed for going over their rate
+  pub closed: u64,
+  // connection attempts turned away for coming too fast from one address
+  pub refused: u64,
+}
+
+// Fills up at a steady rate to at most its burst size
+struct TokenBucket {
+  rate: f64,
+  burst: f64,
+  tokens: f64,
+  updated: Instant,
+}
+
+impl TokenBucket {
+  fn new(rate: f64, burst: f64) -> TokenBucket {
+    TokenBucket {
+      rate,
+      burst,
+      tokens: burst,
+      updated: Instant::now(),
+    }
+  }
+
+  fn refill(&mut self) {
+    let now = Instant::now();
+    let elapsed = now.duration_since(self.updated).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+    self.updated = now;
+  }
+
+  // Anything bigger than the burst needs a full bucket and leaves it in debt, so a large message
+  // can still pass once the client has been quiet for long enough
+  fn has(&mut self, amount: f64) -> bool {
+    self.refill();
+    self.tokens >= amount.min(self.burst)
+  }
+
+  // Takes the amount whether it is there or not, returns how long until the bucket is out of debt
+  fn take(&mut self, amount: f64) -> Duration {
+    self.refill();
+    self.tokens -= amount;
+    if self.tokens >= 0.0 {
+      Duration::ZERO
+    } else {
+      Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+  }
+
+  // a full bucket is no different from a new one, so it can be dropped
+  fn is_full(&mut self) -> bool {
+    self.refill();
+    self.tokens >= self.burst
+  }
+}
+
+struct ClientBuckets {
+  messages: Option<TokenBucket>,
+  bytes: Option<TokenBucket>,
+}
+
+impl ClientBuckets {
+  fn is_full(&mut self) -> bool {
+    self.messages.as_mut().is_none_or(TokenBucket::is_full)
+      && self.bytes.as_mut().is_none_or(TokenBucket::is_full)
+  }
+}
+
+// Per client id, so opening more sessions doesn't buy a client more throughput
+pub struct RateLimits {
+  // (rate, burst), None when that limit is off
+  messages: Option<(f64, f64)>,
+  bytes: Option<(f64, f64)>,
+  action: RateLimitAction,
+  clients: HashMap<u32, ClientBuckets>,
+  stats: RateLimitStats,
+}
+
+impl RateLimits {
+  pub fn new(config: &RateLimitConfig) -> RateLimits {
+    RateLimits {
+      messages: Self::limit(
+        *config.messages_per_sec() as f64,
+        *config.message_burst() as f64,
+      ),
+      bytes: Self::limit(*config.bytes_per_sec() as f64, *config.byte_burst() as f64),
+      action: *config.action(),
+      clients: HashMap::new(),
+      stats: RateLimitStats::default(),
+    }
+  }
+
+  fn limit(rate: f64, burst: f64) -> Option<(f64, f64)> {
+    if rate == 0.0 {
+      None
+    } else if burst == 0.0 {
+      Some((rate, rate))
+    } else {
+      Some((rate, burst))
+    }
+  }
+
+  // Charges a client for a message of the given size
+  pub fn check(&mut self, id: u32, size: usize) -> Verdict {
+    if self.messages.is_none() && self.bytes.is_none() {
+      return Verdict::Allow;
+    }
+    let (messages, bytes) = (self.messages, self.bytes);
+    let buckets = self.clients.entry(id).or_insert_with(|| ClientBuckets {
+      messages: messages.map(|(rate, burst)| TokenBucket::new(rate, burst)),
+      bytes: bytes.map(|(rate, burst)| TokenBucket::new(rate, burst)),
+    });
+    let size = size as f64;
+    let verdict = match self.action {
+      RateLimitAction::Delay => {
+        let wait = buckets
+          .messages
+          .as_mut()
+          .map_or(Duration::ZERO, |bucket| bucket.take(1.0))
+          .max(
+            buckets
+              .bytes
+              .as_mut()
+              .map_or(Duration::ZERO, |bucket| bucket.take(size)),
+          );
+        if wait.is_zero() {
+          return Verdict::Allow;
+        }
+        self.stats.delayed += 1;
+        Verdict::Delay(wait)
+      }
+      action => {
+        let allowed = buckets
+          .messages
+          .as_mut()
+          .is_none_or(|bucket| bucket.has(1.0))
+          && buckets.bytes.as_mut().is_none_or(|bucket| bucket.has(size));
+        if allowed {
+          if let Some(bucket) = buckets.messages.as_mut() {
+            bucket.take(1.0);
+          }
+          if let Some(bucket) = buckets.bytes.as_mut() {
+            bucket.take(size);
+          }
+          return Verdict::Allow;
+        }
+        if action == RateLimitAction::Close {
+          self.stats.closed += 1;
+          Verdict::Close
+        } else {
+          self.stats.dropped += 1;
+          Verdict::Drop
+        }
+      }
+    };
+    self.stats.limited += 1;
+    verdict
+  }
+
+  // Called when a client's last session closes, forgets every client that is back under its rate
+  pub fn release(&mut self) {
+    self.clients.retain(|_, buckets| !buckets.is_full());
+  }
+
+  pub fn stats(&self) -> RateLimitStats {
+    self.stats
+  }
+}
+
+// Connection attempts per source address, checked before anything is read from the socket
+pub struct ConnectionLimiter {
+  limit: Option<(f64, f64)>,
+  addresses: HashMap<IpAddr, TokenBucket>,
+  swept: Instant,
+  refused: u64,
+}
+
+impl ConnectionLimiter {
+  pub fn new(config: &RateLimitConfig) -> ConnectionLimiter {
+    let per_min = *config.connections_per_min() as f64;
+    let burst = match *config.connection_burst() {
+      0 => per_min,
+      burst => burst as f64,
+    };
+    ConnectionLimiter {
+      limit: (per_min > 0.0).then_some((per_min / 60.0, burst)),
+      addresses: HashMap::new(),
+      swept: Instant::now(),
+      refused: 0,
+    }
+  }
+
+  // false if the address has been trying too often
+  pub fn allow(&mut self, address: IpAddr) -> bool {
+    let (rate, burst) = match self.limit {
+      Some(limit) => limit,
+      None => return true,
+    };
+    if self.swept.elapsed() >= SWEEP_INTERVAL {
+      self.addresses.retain(|_, bucket| !bucket.is_full());
+      self.swept = Instant::now();
+    }
+    let bucket = self
+      .addresses
+      .entry(address)
+      .or_insert_with(|| TokenBucket::new(rate, burst));
+    if bucket.has(1.0) {
+      bucket.take(1.0);
+      true
+    } else {
+      self.refused += 1;
+      false
+    }
+  }
+
+  pub fn refused(&self) -> u64 {
+    self.refused
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn limits(toml: &str) -> RateLimits {
+    RateLimits::new(&toml::from_str(toml).unwrap())
+  }
+
+  fn connection_limiter(toml: &str) -> ConnectionLimiter {
+    ConnectionLimiter::new(&toml::from_str(toml).unwrap())
+  }
+
+  #[test]
+  fn allows_everything_without_limits() {
+    let mut limits = limits("");
+    for _ in 0..100 {
+      assert_eq!(limits.check(1, 1 << 20), Verdict::Allow);
+    }
+  }
+
+  #[test]
+  fn drops_messages_over_the_burst() {
+    let mut limits = limits("messages_per_sec = 1\nmessage_burst = 2\naction = \"drop\"");
+    assert_eq!(limits.check(1, 10), Verdict::Allow);
+    assert_eq!(limits.check(1, 10), Verdict::Allow);
+    assert_eq!(limits.check(1, 10), Verdict::Drop);
+    // every client has its own bucket
+    assert_eq!(limits.check(2, 10), Verdict::Allow);
+    let stats = limits.stats();
+    assert_eq!((stats.limited, stats.dropped, stats.closed), (1, 1, 0));
+  }
+
+  #[test]
+  fn a_dropped_message_costs_nothing() {
+    let mut limits = limits("bytes_per_sec = 1\nbyte_burst = 100\naction = \"drop\"");
+    assert_eq!(limits.check(1, 60), Verdict::Allow);
+    assert_eq!(limits.check(1, 50), Verdict::Drop);
+    assert_eq!(limits.check(1, 40), Verdict::Allow);
+    assert_eq!(limits.check(1, 1), Verdict::Drop);
+  }
+
+  #[test]
+  fn messages_over_the_burst_need_a_full_bucket() {
+    let mut limits = limits("bytes_per_sec = 1\nbyte_burst = 100\naction = \"drop\"");
+    assert_eq!(limits.check(1, 250), Verdict::Allow);
+    // and leave it in debt
+    assert_eq!(limits.check(1, 1), Verdict::Drop);
+    assert_eq!(limits.check(2, 1), Verdict::Allow);
+    assert_eq!(limits.check(2, 250), Verdict::Drop);
+  }
+
+  #[test]
+  fn closes_clients_over_the_rate() {
+    let mut limits = limits("messages_per_sec = 1\naction = \"close\"");
+    assert_eq!(limits.check(1, 10), Verdict::Allow);
+    assert_eq!(limits.check(1, 10), Verdict::Close);
+    assert_eq!(limits.stats().closed, 1);
+  }
+
+  #[test]
+  fn delays_until_the_client_is_back_under_its_rate() {
+    let mut limits = limits("bytes_per_sec = 100\nbyte_burst = 100\naction = \"delay\"");
+    assert_eq!(limits.check(1, 100), Verdict::Allow);
+    match limits.check(1, 100) {
+      Verdict::Delay(wait) => {
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1))
+      }
+      other => panic!("got {:?}", other),
+    }
+    assert_eq!(limits.stats().delayed, 1);
+  }
+
+  #[test]
+  fn forgets_clients_back_under_their_rate() {
+    let mut slow = limits("messages_per_sec = 1\nmessage_burst = 2");
+    slow.check(1, 10);
+    slow.release();
+    // still a token short
+    assert_eq!(slow.clients.len(), 1);
+    let mut fast = limits("messages_per_sec = 1000\nmessage_burst = 2");
+    fast.check(1, 10);
+    std::thread::sleep(Duration::from_millis(5));
+    fast.release();
+    assert!(fast.clients.is_empty());
+  }
+
+  #[test]
+  fn refuses_addresses_that_connect_too_often() {
+    let mut limiter = connection_limiter("connections_per_min = 2");
+    let address = IpAddr::from([192, 0, 2, 1]);
+    assert!(limiter.allow(address));
+    assert!(limiter.allow(address));
+    assert!(!limiter.allow(address));
+    assert!(limiter.allow(IpAddr::from([192, 0, 2, 2])));
+    assert_eq!(limiter.refused(), 1);
+    let mut unlimited = connection_limiter("");
+    assert!((0..100).all(|_| unlimited.allow(address)));
+  }
+}
@@ -0,0 +1,81 @@
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub type ReceiptMap = Arc<Mutex<Receipts>>;
+
+// how many message ids are remembered per recipient, the oldest are forgotten first
+const MAX_TRACKED_IDS: usize = 1024;
+
+// Which sender each message id came from, per recipient that was handed the message. Receipts are
+// only passed on to those senders, so a client can't send receipts for messages it never got.
+#[derive(Debug, Default)]
+pub struct Receipts {
+  delivered: HashMap<u32, VecDeque<(Value, u32)>>,
+}
+
+impl Receipts {
+  pub fn record(&mut self, recipient: u32, id: &Value, sender: u32) {
+    let ids = self.delivered.entry(recipient).or_default();
+    if ids.contains(&(id.clone(), sender)) {
+      return;
+    }
+    if ids.len() >= MAX_TRACKED_IDS {
+      ids.pop_front();
+    }
+    ids.push_back((id.clone(), sender));
+  }
+
+  // Every client that sent the recipient a message with this id, ids are only unique per sender
+  pub fn senders(&self, recipient: u32, id: &Value) -> Vec<u32> {
+    let mut senders: Vec<u32> = self
+      .delivered
+      .get(&recipient)
+      .into_iter()
+      .flatten()
+      .filter(|(delivered, _)| delivered == id)
+      .map(|(_, sender)| *sender)
+      .collect();
+    senders.sort_unstable();
+    senders
+  }
+
+  // For when the recipient's last session closes
+  pub fn forget(&mut self, recipient: u32) {
+    self.delivered.remove(&recipient);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn finds_the_senders_of_a_delivered_id() {
+    let mut receipts = Receipts::default();
+    receipts.record(2, &json!("m1"), 1);
+    receipts.record(2, &json!("m1"), 1);
+    receipts.record(2, &json!("m1"), 3);
+    receipts.record(2, &json!(7), 1);
+    assert_eq!(receipts.senders(2, &json!("m1")), [1, 3]);
+    assert_eq!(receipts.senders(2, &json!(7)), [1]);
+    // nothing with that id went to client 2, or anything to client 4
+    assert!(receipts.senders(2, &json!("m2")).is_empty());
+    assert!(receipts.senders(4, &json!("m1")).is_empty());
+    receipts.forget(2);
+    assert!(receipts.senders(2, &json!("m1")).is_empty());
+  }
+
+  #[test]
+  fn forgets_the_oldest_ids_first() {
+    let mut receipts = Receipts::default();
+    for n in 0..=MAX_TRACKED_IDS {
+      receipts.record(2, &json!(n), 1);
+    }
+    assert!(receipts.senders(2, &json!(0)).is_empty());
+    assert_eq!(receipts.senders(2, &json!(1)), [1]);
+    assert_eq!(receipts.senders(2, &json!(MAX_TRACKED_IDS)), [1]);
+  }
+}
@@ -0,0 +1,169 @@
+use crate::server::routing::SessionKey;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+pub type RoomMap = Arc<RwLock<Rooms>>;
+
+// What other members are told about a client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Presence {
+  Join,
+  Leave,
+  // dropped for missing pongs or not answering a close frame
+  Timeout,
+}
+
+impl Presence {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Presence::Join => "join",
+      Presence::Leave => "leave",
+      Presence::Timeout => "timeout",
+    }
+  }
+}
+
+// Room membership. Sessions join, but members are client ids: an id is in a room while any of its
+// sessions is, so presence events only fire for its first join and last leave.
+#[derive(Debug, Default)]
+pub struct Rooms {
+  members: HashMap<String, HashMap<u32, HashSet<u64>>>,
+  // the same thing the other way round, so a closing session doesn't have to scan every room
+  memberships: HashMap<SessionKey, HashSet<String>>,
+}
+
+impl Rooms {
+  // true if the client wasn't in the room before
+  pub fn join(&mut self, room: &str, session: SessionKey) -> bool {
+    let (id, session_id) = session;
+    self
+      .memberships
+      .entry(session)
+      .or_default()
+      .insert(String::from(room));
+    let sessions = self
+      .members
+      .entry(String::from(room))
+      .or_default()
+      .entry(id)
+      .or_default();
+    let first = sessions.is_empty();
+    sessions.insert(session_id);
+    first
+  }
+
+  // true if that was the client's last session in the room
+  pub fn leave(&mut self, room: &str, session: SessionKey) -> bool {
+    if let Some(rooms) = self.memberships.get_mut(&session) {
+      rooms.remove(room);
+      if rooms.is_empty() {
+        self.memberships.remove(&session);
+      }
+    }
+    Self::remove_member(&mut self.members, room, session)
+  }
+
+  // Takes a session out of all its rooms and returns the ones its client has now left
+  pub fn remove_session(&mut self, session: SessionKey) -> Vec<String> {
+    match self.memberships.remove(&session) {
+      Some(rooms) => rooms
+        .into_iter()
+        .filter(|room| Self::remove_member(&mut self.members, room, session))
+        .collect(),
+      None => Vec::new(),
+    }
+  }
+
+  pub fn names(&self) -> Vec<String> {
+    self.members.keys().cloned().collect()
+  }
+
+  pub fn members(&self, room: &str) -> Vec<u32> {
+    let mut members: Vec<u32> = self
+      .members
+      .get(room)
+      .map_or_else(Vec::new, |members| members.keys().copied().collect());
+    members.sort_unstable();
+    members
+  }
+
+  pub fn sessions(&self, room: &str) -> Vec<SessionKey> {
+    self.members.get(room).map_or_else(Vec::new, |members| {
+      members
+        .iter()
+        .flat_map(|(id, sessions)| sessions.iter().map(move |session| (*id, *session)))
+        .collect()
+    })
+  }
+
+  // empty rooms are removed so the map doesn't grow forever
+  fn remove_member(
+    members: &mut HashMap<String, HashMap<u32, HashSet<u64>>>,
+    room: &str,
+    session: SessionKey,
+  ) -> bool {
+    let (id, session_id) = session;
+    let room_members = match members.get_mut(room) {
+      Some(room_members) => room_members,
+      None => return false,
+    };
+    let left = match room_members.get_mut(&id) {
+      Some(sessions) => sessions.remove(&session_id) && sessions.is_empty(),
+      None => false,
+    };
+    if left {
+      room_members.remove(&id);
+      if room_members.is_empty() {
+        members.remove(room);
+      }
+    }
+    left
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn fires_presence_on_the_first_join_and_last_leave() {
+    let mut rooms = Rooms::default();
+    assert!(rooms.join("lobby", (1, 10)));
+    assert!(!rooms.join("lobby", (1, 11)));
+    assert!(!rooms.join("lobby", (1, 11)));
+    assert!(rooms.join("lobby", (2, 20)));
+    assert_eq!(rooms.members("lobby"), [1, 2]);
+    let mut sessions = rooms.sessions("lobby");
+    sessions.sort_unstable();
+    assert_eq!(sessions, [(1, 10), (1, 11), (2, 20)]);
+
+    assert!(!rooms.leave("lobby", (1, 10)));
+    assert!(rooms.leave("lobby", (1, 11)));
+    assert!(!rooms.leave("lobby", (1, 11)));
+    assert!(!rooms.leave("attic", (2, 20)));
+    assert_eq!(rooms.members("lobby"), [2]);
+  }
+
+  #[test]
+  fn a_closed_session_leaves_all_its_rooms() {
+    let mut rooms = Rooms::default();
+    rooms.join("lobby", (1, 10));
+    rooms.join("attic", (1, 10));
+    rooms.join("attic", (1, 11));
+    rooms.join("lobby", (2, 20));
+    let mut left = rooms.remove_session((1, 10));
+    left.sort_unstable();
+    // the client is still in the attic through its other session
+    assert_eq!(left, ["lobby"]);
+    assert_eq!(rooms.members("attic"), [1]);
+    assert!(rooms.remove_session((1, 10)).is_empty());
+
+    assert_eq!(rooms.remove_session((1, 11)), ["attic"]);
+    assert_eq!(rooms.names(), ["lobby"]);
+    rooms.remove_session((2, 20));
+    // empty rooms and sessions are not kept around
+    assert!(rooms.members.is_empty());
+    assert!(rooms.memberships.is_empty());
+  }
+}
@@ -0,0 +1,286 @@
+use crate::server::connectedclient::ConnectedClient;
+use crate::server::offline::{OfflineMap, OfflineQueues};
+use crate::server::ratelimit::{RateLimits, RateMap};
+use crate::server::receipts::ReceiptMap;
+use crate::server::rooms::{RoomMap, Rooms};
+use crate::utils::config::ServerConfig;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+
+// Every connection registered under a client id, keyed by a session number that is unique to the
+// connection so cleanup never touches another connection's entry
+pub type Sessions = HashMap<u64, Mutex<ConnectedClient>>;
+pub type ClientMap = Arc<RwLock<HashMap<u32, Sessions>>>;
+pub type TopicMap = Arc<RwLock<Topics>>;
+
+// The maps messages are routed through, shared by every connection
+#[derive(Clone)]
+pub struct Registry {
+  pub clients: ClientMap,
+  pub topics: TopicMap,
+  pub rooms: RoomMap,
+  pub offline: OfflineMap,
+  pub rates: RateMap,
+  pub receipts: ReceiptMap,
+}
+
+impl Registry {
+  pub fn new(config: &ServerConfig) -> Registry {
+    Registry {
+      clients: ClientMap::default(),
+      topics: TopicMap::default(),
+      rooms: RoomMap::default(),
+      offline: Arc::new(Mutex::new(OfflineQueues::new(config.offline()))),
+      rates: Arc::new(Mutex::new(RateLimits::new(config.rate_limit()))),
+      receipts: ReceiptMap::default(),
+    }
+  }
+}
+
+// What became of a message for one of its recipients. Ordered from best to worst, a client with
+// several sessions gets the best of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Delivery {
+  // handed to the client's connection
+  Delivered,
+  // kept in the offline queue until the client is back
+  Queued,
+  QueueFull,
+  // the client's connection has failed
+  Unreachable,
+  UnknownRecipient,
+}
+
+impl Delivery {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Delivery::Delivered => "delivered",
+      Delivery::Queued => "queued",
+      Delivery::QueueFull => "queue_full",
+      Delivery::Unreachable => "unreachable",
+      Delivery::UnknownRecipient => "unknown_recipient",
+    }
+  }
+}
+
+// One connection: its client id and session number
+pub type SessionKey = (u32, u64);
+
+// Where a message is headed
+#[derive(Debug, Clone, PartialEq)]
+pub enum Route {
+  // every session of each of these ids
+  Clients(Vec<u32>),
+  // every session subscribed to the topic, the sender's included
+  Topic(String),
+  // every session in the room except the sender's, and its client's other sessions
+  Room(String),
+  // every session except the sender's
+  Broadcast,
+}
+
+// Topic subscriptions, held per session so one connection closing leaves the id's other sessions
+// subscribed
+#[derive(Debug, Default)]
+pub struct Topics {
+  subscribers: HashMap<String, HashSet<SessionKey>>,
+  // the same thing the other way round, so a closing session doesn't have to scan every topic
+  subscriptions: HashMap<SessionKey, HashSet<String>>,
+}
+
+impl Topics {
+  // false if the session was already subscribed
+  pub fn subscribe(&mut self, topic: &str, session: SessionKey) -> bool {
+    self
+      .subscriptions
+      .entry(session)
+      .or_default()
+      .insert(String::from(topic));
+    self
+      .subscribers
+      .entry(String::from(topic))
+      .or_default()
+      .insert(session)
+  }
+
+  // false if the session wasn't subscribed
+  pub fn unsubscribe(&mut self, topic: &str, session: SessionKey) -> bool {
+    if let Some(topics) = self.subscriptions.get_mut(&session) {
+      topics.remove(topic);
+      if topics.is_empty() {
+        self.subscriptions.remove(&session);
+      }
+    }
+    Self::remove_subscriber(&mut self.subscribers, topic, session)
+  }
+
+  pub fn subscribers(&self, topic: &str) -> Vec<SessionKey> {
+    self
+      .subscribers
+      .get(topic)
+      .map_or_else(Vec::new, |sessions| sessions.iter().copied().collect())
+  }
+
+  // Drops everything a session is subscribed to, for when its connection goes away
+  pub fn remove_session(&mut self, session: SessionKey) {
+    if let Some(topics) = self.subscriptions.remove(&session) {
+      for topic in topics {
+        Self::remove_subscriber(&mut self.subscribers, &topic, session);
+      }
+    }
+  }
+
+  // topics nobody listens to any more are removed so the map doesn't grow forever
+  fn remove_subscriber(
+    subscribers: &mut HashMap<String, HashSet<SessionKey>>,
+    topic: &str,
+    session: SessionKey,
+  ) -> bool {
+    match subscribers.get_mut(topic) {
+      Some(sessions) => {
+        let removed = sessions.remove(&session);
+        if sessions.is_empty() {
+          subscribers.remove(topic);
+        }
+        removed
+      }
+      None => false,
+    }
+  }
+}
+
+// Picks out the sessions a message goes to, along with the ids it was addressed to that aren't
+// connected
+pub fn resolve<'a>(
+  route: &Route,
+  sender: SessionKey,
+  clients: &'a HashMap<u32, Sessions>,
+  topics: &Topics,
+  rooms: &Rooms,
+) -> (Vec<(u32, &'a Mutex<ConnectedClient>)>, Vec<u32>) {
+  let mut missing = Vec::new();
+  let recipients = match route {
+    Route::Clients(ids) => {
+      let mut recipients = Vec::new();
+      for id in ids {
+        match clients.get(id) {
+          // an id can have several sessions, each of them gets the message
+          Some(sessions) => recipients.extend(sessions.values().map(|client| (*id, client))),
+          None => missing.push(*id),
+        }
+      }
+      recipients
+    }
+    Route::Topic(topic) => lookup(clients, topics.subscribers(topic)),
+    Route::Room(room) => {
+      // a client with several sessions is one member, it isn't told about itself
+      let (sender_id, _) = sender;
+      let mut members = rooms.sessions(room);
+      members.retain(|(member, _)| *member != sender_id);
+      lookup(clients, members)
+    }
+    Route::Broadcast => clients
+      .iter()
+      .flat_map(|(id, sessions)| {
+        sessions
+          .iter()
+          .filter(move |(session, _)| (*id, **session) != sender)
+          .map(move |(_, client)| (*id, client))
+      })
+      .collect(),
+  };
+  (recipients, missing)
+}
+
+// sessions that closed since the keys were taken are skipped
+fn lookup(
+  clients: &HashMap<u32, Sessions>,
+  keys: Vec<SessionKey>,
+) -> Vec<(u32, &Mutex<ConnectedClient>)> {
+  keys
+    .into_iter()
+    .filter_map(|(id, session)| {
+      clients
+        .get(&id)
+        .and_then(|sessions| sessions.get(&session))
+        .map(|client| (id, client))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::message::MessageFormat;
+  use crate::server::outbound::Outbound;
+  use crate::utils::config::OverflowPolicy;
+
+  #[test]
+  fn subscribes_sessions_to_topics() {
+    let mut topics = Topics::default();
+    assert!(topics.subscribe("news", (1, 10)));
+    assert!(!topics.subscribe("news", (1, 10)));
+    assert!(topics.subscribe("news", (1, 11)));
+    assert!(topics.subscribe("news", (2, 20)));
+    let mut subscribers = topics.subscribers("news");
+    subscribers.sort_unstable();
+    assert_eq!(subscribers, [(1, 10), (1, 11), (2, 20)]);
+    assert!(topics.subscribers("sport").is_empty());
+
+    assert!(topics.unsubscribe("news", (1, 10)));
+    assert!(!topics.unsubscribe("news", (1, 10)));
+    assert!(!topics.unsubscribe("sport", (2, 20)));
+    let mut subscribers = topics.subscribers("news");
+    subscribers.sort_unstable();
+    assert_eq!(subscribers, [(1, 11), (2, 20)]);
+  }
+
+  #[test]
+  fn forgets_a_closed_session() {
+    let mut topics = Topics::default();
+    topics.subscribe("news", (1, 10));
+    topics.subscribe("sport", (1, 10));
+    topics.subscribe("sport", (2, 20));
+    topics.remove_session((1, 10));
+    assert!(topics.subscribers("news").is_empty());
+    assert_eq!(topics.subscribers("sport"), [(2, 20)]);
+    topics.unsubscribe("sport", (2, 20));
+    // nothing is left behind for topics and sessions that are gone
+    assert!(topics.subscribers.is_empty());
+    assert!(topics.subscriptions.is_empty());
+  }
+
+  fn connected(id: u32) -> Mutex<ConnectedClient> {
+    let outbound = Arc::new(Outbound::new(1, OverflowPolicy::DropNewest));
+    let (close_tx, _) = tokio::sync::mpsc::unbounded_channel();
+    Mutex::new(ConnectedClient::new(
+      id,
+      outbound,
+      close_tx,
+      MessageFormat::Json,
+      None,
+    ))
+  }
+
+  #[test]
+  fn room_messages_skip_every_session_of_the_sender() {
+    let mut clients: HashMap<u32, Sessions> = HashMap::new();
+    let mut rooms = Rooms::default();
+    for (id, session) in [(1, 10), (1, 11), (2, 20), (3, 30)] {
+      clients.entry(id).or_default().insert(session, connected(id));
+      rooms.join("lobby", (id, session));
+    }
+    rooms.leave("lobby", (3, 30));
+    let (recipients, missing) = resolve(
+      &Route::Room(String::from("lobby")),
+      (1, 10),
+      &clients,
+      &Topics::default(),
+      &rooms,
+    );
+    let ids: Vec<u32> = recipients.iter().map(|(id, _)| *id).collect();
+    assert_eq!(ids, [2]);
+    assert!(missing.is_empty());
+  }
+}
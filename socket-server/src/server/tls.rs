@@ -0,0 +1,181 @@
+use crate::utils::config::TlsConfig;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{self, RootCertStore};
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+
+#[derive(Debug)]
+pub enum TlsError {
+  Read(PathBuf, std::io::Error),
+  NoCertificates(PathBuf),
+  NoPrivateKey(PathBuf),
+  Rustls(rustls::Error),
+  ClientVerifier(String),
+}
+
+impl fmt::Display for TlsError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      TlsError::Read(path, err) => write!(f, "Could not read {}: {}", path.display(), err),
+      TlsError::NoCertificates(path) => write!(f, "No certificates in {}", path.display()),
+      TlsError::NoPrivateKey(path) => write!(f, "No private key in {}", path.display()),
+      TlsError::Rustls(err) => write!(f, "TLS error: {}", err),
+      TlsError::ClientVerifier(err) => write!(f, "Invalid client CA: {}", err),
+    }
+  }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<rustls::Error> for TlsError {
+  fn from(err: rustls::Error) -> TlsError {
+    TlsError::Rustls(err)
+  }
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+  let file = File::open(path).map_err(|err| TlsError::Read(path.to_path_buf(), err))?;
+  let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|err| TlsError::Read(path.to_path_buf(), err))?;
+  if certs.is_empty() {
+    return Err(TlsError::NoCertificates(path.to_path_buf()));
+  }
+  Ok(certs)
+}
+
+fn load_certified_key(
+  provider: &CryptoProvider,
+  cert_path: &Path,
+  key_path: &Path,
+) -> Result<CertifiedKey, TlsError> {
+  let certs = read_certificates(cert_path)?;
+  let file = File::open(key_path).map_err(|err| TlsError::Read(key_path.to_path_buf(), err))?;
+  let key = rustls_pemfile::private_key(&mut BufReader::new(file))
+    .map_err(|err| TlsError::Read(key_path.to_path_buf(), err))?
+    .ok_or_else(|| TlsError::NoPrivateKey(key_path.to_path_buf()))?;
+  let signing_key = provider.key_provider.load_private_key(key)?;
+  let certified_key = CertifiedKey::new(certs, signing_key);
+  // catches a key that was swapped without its certificate or the other way round
+  certified_key.keys_match()?;
+  Ok(certified_key)
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+  std::fs::metadata(path)
+    .and_then(|meta| meta.modified())
+    .ok()
+}
+
+// Hands out the current certificate for every handshake, so a reload only affects connections
+// made after it
+#[derive(Debug)]
+pub struct CertificateResolver {
+  provider: Arc<CryptoProvider>,
+  cert_path: PathBuf,
+  key_path: PathBuf,
+  current: RwLock<Arc<CertifiedKey>>,
+  modified: RwLock<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl CertificateResolver {
+  fn new(
+    provider: Arc<CryptoProvider>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+  ) -> Result<CertificateResolver, TlsError> {
+    let modified = (modified_time(&cert_path), modified_time(&key_path));
+    let certified_key = load_certified_key(&provider, &cert_path, &key_path)?;
+    Ok(CertificateResolver {
+      provider,
+      cert_path,
+      key_path,
+      current: RwLock::new(Arc::new(certified_key)),
+      modified: RwLock::new(modified),
+    })
+  }
+
+  // Reloads the certificate and key if either file changed since the last load. A broken pair is
+  // logged and the old certificate stays in use.
+  pub fn reload_if_changed(&self) {
+    let modified = (
+      modified_time(&self.cert_path),
+      modified_time(&self.key_path),
+    );
+    if *self.modified.read().unwrap() == modified {
+      return;
+    }
+    match load_certified_key(&self.provider, &self.cert_path, &self.key_path) {
+      Ok(certified_key) => {
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        *self.modified.write().unwrap() = modified;
+        info!("Reloaded TLS certificate {}", self.cert_path.display());
+      }
+      Err(err) => warn!("Keeping the old TLS certificate: {}", err),
+    }
+  }
+
+  pub async fn watch(self: Arc<Self>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+      ticker.tick().await;
+      self.reload_if_changed();
+    }
+  }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+  fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+    Some(Arc::clone(&self.current.read().unwrap()))
+  }
+}
+
+// None when no certificate is configured and the server should speak plain ws
+pub fn create_acceptor(
+  config: &TlsConfig,
+) -> Result<Option<(TlsAcceptor, Arc<CertificateResolver>)>, TlsError> {
+  let (cert_path, key_path) = match (config.cert(), config.key()) {
+    (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+    _ => return Ok(None),
+  };
+  let provider = Arc::new(ring::default_provider());
+  let resolver = Arc::new(CertificateResolver::new(
+    Arc::clone(&provider),
+    cert_path.clone(),
+    key_path.clone(),
+  )?);
+  let builder = rustls::ServerConfig::builder_with_provider(Arc::clone(&provider))
+    .with_safe_default_protocol_versions()?;
+  let builder = match config.client_ca() {
+    Some(client_ca) => {
+      let mut roots = RootCertStore::empty();
+      for cert in read_certificates(client_ca)? {
+        roots.add(cert)?;
+      }
+      let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+      let verifier = if *config.client_cert_optional() {
+        verifier.allow_unauthenticated()
+      } else {
+        verifier
+      };
+      builder.with_client_cert_verifier(
+        verifier
+          .build()
+          .map_err(|err| TlsError::ClientVerifier(err.to_string()))?,
+      )
+    }
+    None => builder.with_no_client_auth(),
+  };
+  let server_config =
+    builder.with_cert_resolver(Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>);
+  Ok(Some((TlsAcceptor::from(Arc::new(server_config)), resolver)))
+}
@@ -0,0 +1,748 @@
+use crate::utils::utils::Opts;
+use getset::Getters;
+use serde::Deserialize;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::Level;
+
+pub const DEFAULT_PORT: u16 = 8080;
+pub const DEFAULT_KEY: &str = "1234567890";
+pub const DEFAULT_LOG_LEVEL: &str = "trace";
+pub const DEFAULT_STATS_INTERVAL_MS: u64 = 60000;
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+pub const DEFAULT_CLOSE_TIMEOUT_MS: u64 = 5000;
+pub const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 10000;
+pub const DEFAULT_MAX_MISSED_PONGS: u32 = 3;
+pub const DEFAULT_CERT_RELOAD_INTERVAL_MS: u64 = 10000;
+pub const DEFAULT_TOKEN_TTL_SECS: u64 = 24 * 60 * 60;
+pub const DEFAULT_OFFLINE_MAX_MESSAGES: usize = 100;
+pub const DEFAULT_OFFLINE_TTL_SECS: u64 = 5 * 60;
+pub const DEFAULT_OUTBOUND_QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug)]
+pub enum ConfigError {
+  Read(PathBuf, std::io::Error),
+  Parse(PathBuf, toml::de::Error),
+  Invalid(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ConfigError::Read(path, err) => write!(f, "Could not read {}: {}", path.display(), err),
+      ConfigError::Parse(path, err) => write!(f, "Could not parse {}: {}", path.display(), err),
+      ConfigError::Invalid(reason) => write!(f, "Invalid configuration: {}", reason),
+    }
+  }
+}
+
+// Everything the server needs to run. Built from the defaults below, then the TOML file passed
+// with -f, then whatever was given on the command line.
+#[derive(Debug, Clone, Deserialize, Getters)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+  #[getset(get = "pub")]
+  threads: usize,
+  #[getset(get = "pub")]
+  addresses: Vec<IpAddr>,
+  #[getset(get = "pub")]
+  port: u16,
+  // subprotocols clients may pick from in Sec-WebSocket-Protocol
+  #[getset(get = "pub")]
+  protocols: Vec<String>,
+  #[getset(get = "pub")]
+  log: LogConfig,
+  #[getset(get = "pub")]
+  limits: LimitsConfig,
+  #[getset(get = "pub")]
+  heartbeat: HeartbeatConfig,
+  #[getset(get = "pub")]
+  auth: AuthConfig,
+  #[getset(get = "pub")]
+  tls: TlsConfig,
+  #[getset(get = "pub")]
+  compression: CompressionConfig,
+  #[getset(get = "pub")]
+  offline: OfflineConfig,
+  #[getset(get = "pub")]
+  outbound: OutboundConfig,
+  #[getset(get = "pub")]
+  rate_limit: RateLimitConfig,
+}
+
+#[derive(Debug, Clone, Deserialize, Getters)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+  #[getset(get = "pub")]
+  level: String,
+  // stdout when not set
+  #[getset(get = "pub")]
+  file: Option<PathBuf>,
+  // 0 turns the periodic stats line off
+  stats_interval_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Getters)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+  #[getset(get = "pub")]
+  max_message_size: usize,
+  #[getset(get = "pub")]
+  max_frame_size: usize,
+  // 0 means no limit
+  #[getset(get = "pub")]
+  max_connections: usize,
+  close_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Getters)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+  // 0 turns the heartbeat off
+  interval_ms: u64,
+  #[getset(get = "pub")]
+  max_missed_pongs: u32,
+}
+
+#[derive(Clone, Deserialize, Getters)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+  // signs the tokens clients register with
+  #[getset(get = "pub")]
+  key: String,
+  // how long tokens made with --issue_token stay valid
+  #[getset(get = "pub")]
+  token_ttl_secs: u64,
+  #[getset(get = "pub")]
+  duplicate_ids: DuplicateIdPolicy,
+  // lets the server run with DEFAULT_KEY, which anyone can sign tokens with. Local testing only.
+  #[getset(get = "pub")]
+  allow_default_key: bool,
+}
+
+// What happens when a client registers with an id that is already connected
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateIdPolicy {
+  // turn the new connection away
+  Reject,
+  // close the old session and keep the new one
+  Replace,
+  // keep both, messages for the id go to every session
+  Multiple,
+}
+
+impl FromStr for DuplicateIdPolicy {
+  type Err = ();
+
+  fn from_str(policy: &str) -> Result<DuplicateIdPolicy, ()> {
+    match policy {
+      "reject" => Ok(DuplicateIdPolicy::Reject),
+      "replace" => Ok(DuplicateIdPolicy::Replace),
+      "multiple" => Ok(DuplicateIdPolicy::Multiple),
+      _ => Err(()),
+    }
+  }
+}
+
+// TLS is on when both cert and key are set
+#[derive(Debug, Clone, Deserialize, Getters)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+  #[getset(get = "pub")]
+  cert: Option<PathBuf>,
+  #[getset(get = "pub")]
+  key: Option<PathBuf>,
+  // clients have to present a certificate signed by one of these when set
+  #[getset(get = "pub")]
+  client_ca: Option<PathBuf>,
+  #[getset(get = "pub")]
+  client_cert_optional: bool,
+  // how often the cert and key files are checked for changes, 0 turns reloading off
+  cert_reload_interval_ms: u64,
+}
+
+// permessage-deflate is only used when the client offers it
+#[derive(Debug, Clone, Deserialize, Getters)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+  #[getset(get = "pub")]
+  permessage_deflate: bool,
+  // start every message we send with a fresh window, trading ratio for memory
+  #[getset(get = "pub")]
+  server_no_context_takeover: bool,
+  // ask clients to do the same for what they send
+  #[getset(get = "pub")]
+  client_no_context_takeover: bool,
+  #[getset(get = "pub")]
+  server_max_window_bits: u8,
+  #[getset(get = "pub")]
+  client_max_window_bits: u8,
+}
+
+// Store and forward for clients that drop off for a moment. Only ids that were connected get a
+// queue, and it is thrown away once they have been gone for longer than the ttl.
+#[derive(Debug, Clone, Deserialize, Getters)]
+#[serde(default, deny_unknown_fields)]
+pub struct OfflineConfig {
+  #[getset(get = "pub")]
+  enabled: bool,
+  // per client, the oldest messages are dropped to make room
+  #[getset(get = "pub")]
+  max_messages: usize,
+  ttl_secs: u64,
+}
+
+// Every connection has its own queue of messages waiting to be written
+#[derive(Debug, Clone, Deserialize, Getters)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundConfig {
+  // in messages
+  #[getset(get = "pub")]
+  queue_size: usize,
+  #[getset(get = "pub")]
+  overflow: OverflowPolicy,
+  // tell JSON senders which recipients couldn't be written to
+  #[getset(get = "pub")]
+  notify_unreachable: bool,
+}
+
+// What happens to a message for a client whose outbound queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+  // make room by dropping the message that has waited longest
+  DropOldest,
+  // drop the new message
+  DropNewest,
+  // drop everything queued and close the client with 1008
+  Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+  type Err = ();
+
+  fn from_str(policy: &str) -> Result<OverflowPolicy, ()> {
+    match policy {
+      "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+      "drop_newest" => Ok(OverflowPolicy::DropNewest),
+      "disconnect" => Ok(OverflowPolicy::Disconnect),
+      _ => Err(()),
+    }
+  }
+}
+
+// Token buckets, per client id for messages and per source address for connection attempts.
+// A rate of 0 turns that limit off, a burst of 0 allows one second's worth (one minute's for
+// connections).
+#[derive(Debug, Clone, Deserialize, Getters)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+  #[getset(get = "pub")]
+  messages_per_sec: u32,
+  #[getset(get = "pub")]
+  message_burst: u32,
+  #[getset(get = "pub")]
+  bytes_per_sec: u64,
+  #[getset(get = "pub")]
+  byte_burst: u64,
+  #[getset(get = "pub")]
+  action: RateLimitAction,
+  #[getset(get = "pub")]
+  connections_per_min: u32,
+  #[getset(get = "pub")]
+  connection_burst: u32,
+}
+
+// What happens to a message from a client that is over its rate
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitAction {
+  // throw the message away
+  Drop,
+  // pass it on, but stop reading from the client until it is back under the rate
+  Delay,
+  // close the client with 1008
+  Close,
+}
+
+impl FromStr for RateLimitAction {
+  type Err = ();
+
+  fn from_str(action: &str) -> Result<RateLimitAction, ()> {
+    match action {
+      "drop" => Ok(RateLimitAction::Drop),
+      "delay" => Ok(RateLimitAction::Delay),
+      "close" => Ok(RateLimitAction::Close),
+      _ => Err(()),
+    }
+  }
+}
+
+impl Default for ServerConfig {
+  fn default() -> Self {
+    ServerConfig {
+      threads: std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+      addresses: vec![IpAddr::from(std::net::Ipv6Addr::LOCALHOST)],
+      port: DEFAULT_PORT,
+      protocols: Vec::new(),
+      log: LogConfig::default(),
+      limits: LimitsConfig::default(),
+      heartbeat: HeartbeatConfig::default(),
+      auth: AuthConfig::default(),
+      tls: TlsConfig::default(),
+      compression: CompressionConfig::default(),
+      offline: OfflineConfig::default(),
+      outbound: OutboundConfig::default(),
+      rate_limit: RateLimitConfig::default(),
+    }
+  }
+}
+
+impl Default for LogConfig {
+  fn default() -> Self {
+    LogConfig {
+      level: String::from(DEFAULT_LOG_LEVEL),
+      file: None,
+      stats_interval_ms: DEFAULT_STATS_INTERVAL_MS,
+    }
+  }
+}
+
+impl Default for LimitsConfig {
+  fn default() -> Self {
+    LimitsConfig {
+      max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+      max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+      max_connections: 0,
+      close_timeout_ms: DEFAULT_CLOSE_TIMEOUT_MS,
+    }
+  }
+}
+
+impl Default for HeartbeatConfig {
+  fn default() -> Self {
+    HeartbeatConfig {
+      interval_ms: DEFAULT_HEARTBEAT_INTERVAL_MS,
+      max_missed_pongs: DEFAULT_MAX_MISSED_PONGS,
+    }
+  }
+}
+
+impl Default for AuthConfig {
+  fn default() -> Self {
+    AuthConfig {
+      key: String::from(DEFAULT_KEY),
+      token_ttl_secs: DEFAULT_TOKEN_TTL_SECS,
+      duplicate_ids: DuplicateIdPolicy::Replace,
+      allow_default_key: false,
+    }
+  }
+}
+
+impl Default for TlsConfig {
+  fn default() -> Self {
+    TlsConfig {
+      cert: None,
+      key: None,
+      client_ca: None,
+      client_cert_optional: false,
+      cert_reload_interval_ms: DEFAULT_CERT_RELOAD_INTERVAL_MS,
+    }
+  }
+}
+
+impl Default for CompressionConfig {
+  fn default() -> Self {
+    CompressionConfig {
+      permessage_deflate: true,
+      server_no_context_takeover: false,
+      client_no_context_takeover: false,
+      server_max_window_bits: 15,
+      client_max_window_bits: 15,
+    }
+  }
+}
+
+impl Default for OfflineConfig {
+  fn default() -> Self {
+    OfflineConfig {
+      enabled: false,
+      max_messages: DEFAULT_OFFLINE_MAX_MESSAGES,
+      ttl_secs: DEFAULT_OFFLINE_TTL_SECS,
+    }
+  }
+}
+
+impl Default for OutboundConfig {
+  fn default() -> Self {
+    OutboundConfig {
+      queue_size: DEFAULT_OUTBOUND_QUEUE_SIZE,
+      overflow: OverflowPolicy::Disconnect,
+      notify_unreachable: true,
+    }
+  }
+}
+
+impl Default for RateLimitConfig {
+  fn default() -> Self {
+    RateLimitConfig {
+      messages_per_sec: 0,
+      message_burst: 0,
+      bytes_per_sec: 0,
+      byte_burst: 0,
+      action: RateLimitAction::Delay,
+      connections_per_min: 0,
+      connection_burst: 0,
+    }
+  }
+}
+
+// written out by hand so the key never ends up in the logs
+impl fmt::Debug for AuthConfig {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("AuthConfig")
+      .field("key", &"<hidden>")
+      .field("token_ttl_secs", &self.token_ttl_secs)
+      .field("duplicate_ids", &self.duplicate_ids)
+      .field("allow_default_key", &self.allow_default_key)
+      .finish()
+  }
+}
+
+impl LogConfig {
+  pub fn max_level(&self) -> Level {
+    // checked in validate, so this never falls back
+    self.level.parse::<Level>().unwrap_or(Level::TRACE)
+  }
+
+  pub fn stats_interval(&self) -> Duration {
+    Duration::from_millis(self.stats_interval_ms)
+  }
+}
+
+impl AuthConfig {
+  pub fn uses_default_key(&self) -> bool {
+    self.key == DEFAULT_KEY
+  }
+}
+
+impl LimitsConfig {
+  pub fn close_timeout(&self) -> Duration {
+    Duration::from_millis(self.close_timeout_ms)
+  }
+}
+
+impl HeartbeatConfig {
+  pub fn interval(&self) -> Duration {
+    Duration::from_millis(self.interval_ms)
+  }
+}
+
+impl TlsConfig {
+  pub fn cert_reload_interval(&self) -> Duration {
+    Duration::from_millis(self.cert_reload_interval_ms)
+  }
+}
+
+impl OfflineConfig {
+  pub fn ttl(&self) -> Duration {
+    Duration::from_secs(self.ttl_secs)
+  }
+}
+
+fn is_token(value: &str) -> bool {
+  !value.is_empty()
+    && value
+      .bytes()
+      .all(|byte| byte.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&byte))
+}
+
+impl ServerConfig {
+  pub fn load(opts: &Opts) -> Result<ServerConfig, ConfigError> {
+    let mut config = match opts.config_file() {
+      Some(path) => {
+        let contents =
+          std::fs::read_to_string(path).map_err(|err| ConfigError::Read(path.clone(), err))?;
+        toml::from_str(&contents).map_err(|err| ConfigError::Parse(path.clone(), err))?
+      }
+      None => ServerConfig::default(),
+    };
+    config.apply_overrides(opts);
+    config.validate()?;
+    Ok(config)
+  }
+
+  fn apply_overrides(&mut self, opts: &Opts) {
+    if let Some(threads) = opts.threads() {
+      self.threads = *threads;
+    }
+    if let Some(addresses) = opts.addresses() {
+      self.addresses = addresses.clone();
+    }
+    if let Some(port) = opts.port() {
+      self.port = *port;
+    }
+    if let Some(key) = opts.key() {
+      self.auth.key = key.clone();
+    }
+    if *opts.allow_default_key() {
+      self.auth.allow_default_key = true;
+    }
+    if let Some(duplicate_ids) = opts.duplicate_ids() {
+      self.auth.duplicate_ids = *duplicate_ids;
+    }
+    if let Some(level) = opts.log_level() {
+      self.log.level = level.clone();
+    }
+    if let Some(file) = opts.log_file() {
+      self.log.file = Some(file.clone());
+    }
+    if let Some(stats_interval) = opts.stats_interval() {
+      self.log.stats_interval_ms = *stats_interval;
+    }
+    if let Some(max_message_size) = opts.max_message_size() {
+      self.limits.max_message_size = *max_message_size;
+    }
+    if let Some(max_frame_size) = opts.max_frame_size() {
+      self.limits.max_frame_size = *max_frame_size;
+    }
+    if let Some(max_connections) = opts.max_connections() {
+      self.limits.max_connections = *max_connections;
+    }
+    if let Some(close_timeout) = opts.close_timeout() {
+      self.limits.close_timeout_ms = *close_timeout;
+    }
+    if let Some(heartbeat_interval) = opts.heartbeat_interval() {
+      self.heartbeat.interval_ms = *heartbeat_interval;
+    }
+    if let Some(max_missed_pongs) = opts.max_missed_pongs() {
+      self.heartbeat.max_missed_pongs = *max_missed_pongs;
+    }
+    if let Some(cert) = opts.tls_cert() {
+      self.tls.cert = Some(cert.clone());
+    }
+    if let Some(key) = opts.tls_key() {
+      self.tls.key = Some(key.clone());
+    }
+    if let Some(client_ca) = opts.tls_client_ca() {
+      self.tls.client_ca = Some(client_ca.clone());
+    }
+    if let Some(protocols) = opts.protocols() {
+      self.protocols = protocols.clone();
+    }
+    if *opts.no_deflate() {
+      self.compression.permessage_deflate = false;
+    }
+    if *opts.offline_queue() {
+      self.offline.enabled = true;
+    }
+    if let Some(queue_size) = opts.queue_size() {
+      self.outbound.queue_size = *queue_size;
+    }
+    if let Some(overflow) = opts.overflow() {
+      self.outbound.overflow = *overflow;
+    }
+    if let Some(message_rate) = opts.message_rate() {
+      self.rate_limit.messages_per_sec = *message_rate;
+    }
+    if let Some(byte_rate) = opts.byte_rate() {
+      self.rate_limit.bytes_per_sec = *byte_rate;
+    }
+    if let Some(action) = opts.rate_limit_action() {
+      self.rate_limit.action = *action;
+    }
+    if let Some(connection_rate) = opts.connection_rate() {
+      self.rate_limit.connections_per_min = *connection_rate;
+    }
+  }
+
+  fn validate(&self) -> Result<(), ConfigError> {
+    if self.threads == 0 {
+      return Err(ConfigError::Invalid("threads must be at least 1"));
+    }
+    if self.addresses.is_empty() {
+      return Err(ConfigError::Invalid("at least one address is needed"));
+    }
+    // protocol names end up in a comma separated header, so they have to be plain HTTP tokens
+    if self.protocols.iter().any(|protocol| !is_token(protocol)) {
+      return Err(ConfigError::Invalid(
+        "protocols must be non-empty and free of spaces and separators",
+      ));
+    }
+    if self.log.level.parse::<Level>().is_err() {
+      return Err(ConfigError::Invalid(
+        "log level must be one of trace, debug, info, warn or error",
+      ));
+    }
+    if self.auth.key.is_empty() {
+      return Err(ConfigError::Invalid("the server key can't be empty"));
+    }
+    if self.auth.uses_default_key() && !self.auth.allow_default_key {
+      return Err(ConfigError::Invalid(
+        "the server key is the public default, set one with -k, SOCKET_SERVER_KEY or [auth] key, \
+         or pass --allow_default_key for local testing",
+      ));
+    }
+    if self.limits.max_message_size == 0 {
+      return Err(ConfigError::Invalid("max_message_size must be at least 1"));
+    }
+    // control frames can carry up to 125 bytes and can't be split
+    if self.limits.max_frame_size < 125 {
+      return Err(ConfigError::Invalid("max_frame_size must be at least 125"));
+    }
+    if self.heartbeat.interval_ms > 0 && self.heartbeat.max_missed_pongs == 0 {
+      return Err(ConfigError::Invalid("max_missed_pongs must be at least 1"));
+    }
+    if self.tls.cert.is_some() != self.tls.key.is_some() {
+      return Err(ConfigError::Invalid("TLS needs both a cert and a key"));
+    }
+    if self.tls.client_ca.is_some() && self.tls.cert.is_none() {
+      return Err(ConfigError::Invalid("client_ca needs TLS to be turned on"));
+    }
+    // zlib can't write raw deflate with an 8 bit window, but can read one
+    if !(9..=15).contains(&self.compression.server_max_window_bits) {
+      return Err(ConfigError::Invalid(
+        "server_max_window_bits must be between 9 and 15",
+      ));
+    }
+    // zlib can't compress with an 8 bit window, so clients won't agree to one
+    if !(9..=15).contains(&self.compression.client_max_window_bits) {
+      return Err(ConfigError::Invalid(
+        "client_max_window_bits must be between 9 and 15",
+      ));
+    }
+    if self.offline.enabled && (self.offline.max_messages == 0 || self.offline.ttl_secs == 0) {
+      return Err(ConfigError::Invalid(
+        "the offline queue needs max_messages and ttl_secs of at least 1",
+      ));
+    }
+    if self.outbound.queue_size == 0 {
+      return Err(ConfigError::Invalid("queue_size must be at least 1"));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(toml: &str) -> ServerConfig {
+    toml::from_str(toml).unwrap()
+  }
+
+  #[test]
+  fn defaults_are_valid_apart_from_the_key() {
+    let mut config = ServerConfig::default();
+    assert!(config.validate().is_err());
+    config.auth.allow_default_key = true;
+    assert!(config.validate().is_ok());
+    assert!(parse("[auth]\nkey = \"secret\"").validate().is_ok());
+    assert!(parse("[auth]\nallow_default_key = true").validate().is_ok());
+  }
+
+  #[test]
+  fn reads_a_file_over_the_defaults() {
+    let config = parse("port = 9000\n[limits]\nmax_connections = 10\n[log]\nlevel = \"info\"");
+    assert_eq!(*config.port(), 9000);
+    assert_eq!(*config.limits().max_connections(), 10);
+    assert_eq!(
+      *config.limits().max_message_size(),
+      DEFAULT_MAX_MESSAGE_SIZE
+    );
+    assert_eq!(config.log().max_level(), Level::INFO);
+    assert_eq!(
+      config.log().stats_interval(),
+      Duration::from_millis(DEFAULT_STATS_INTERVAL_MS)
+    );
+    assert!(toml::from_str::<ServerConfig>("prot = 9000").is_err());
+    assert!(toml::from_str::<ServerConfig>("[limits]\nmax_mesage_size = 1").is_err());
+  }
+
+  #[test]
+  fn rejects_invalid_settings() {
+    for (toml, reason) in [
+      ("threads = 0", "threads must be at least 1"),
+      ("addresses = []", "at least one address is needed"),
+      (
+        "protocols = [\"json\", \"a b\"]",
+        "protocols must be non-empty and free of spaces and separators",
+      ),
+      (
+        "protocols = [\"\"]",
+        "protocols must be non-empty and free of spaces and separators",
+      ),
+      (
+        "[log]\nlevel = \"loud\"",
+        "log level must be one of trace, debug, info, warn or error",
+      ),
+      ("[auth]\nkey = \"\"", "the server key can't be empty"),
+      (
+        "[limits]\nmax_message_size = 0",
+        "max_message_size must be at least 1",
+      ),
+      (
+        "[limits]\nmax_frame_size = 124",
+        "max_frame_size must be at least 125",
+      ),
+      (
+        "[heartbeat]\nmax_missed_pongs = 0",
+        "max_missed_pongs must be at least 1",
+      ),
+      (
+        "[tls]\ncert = \"cert.pem\"",
+        "TLS needs both a cert and a key",
+      ),
+      (
+        "[tls]\nclient_ca = \"ca.pem\"",
+        "client_ca needs TLS to be turned on",
+      ),
+      (
+        "[compression]\nserver_max_window_bits = 8",
+        "server_max_window_bits must be between 9 and 15",
+      ),
+      (
+        "[compression]\nclient_max_window_bits = 16",
+        "client_max_window_bits must be between 9 and 15",
+      ),
+      (
+        "[compression]\nclient_max_window_bits = 8",
+        "client_max_window_bits must be between 9 and 15",
+      ),
+      (
+        "[offline]\nenabled = true\nmax_messages = 0",
+        "the offline queue needs max_messages and ttl_secs of at least 1",
+      ),
+      (
+        "[offline]\nenabled = true\nttl_secs = 0",
+        "the offline queue needs max_messages and ttl_secs of at least 1",
+      ),
+      (
+        "[outbound]\nqueue_size = 0",
+        "queue_size must be at least 1",
+      ),
+    ] {
+      let mut config = parse(toml);
+      config.auth.allow_default_key = true;
+      match config.validate() {
+        Err(ConfigError::Invalid(invalid)) => assert_eq!(invalid, reason),
+        other => panic!("{:?} gave {:?}", toml, other),
+      }
+    }
+  }
+
+  #[test]
+  fn refuses_the_default_key_unless_allowed() {
+    match parse("port = 9000").validate() {
+      Err(ConfigError::Invalid(invalid)) => {
+        assert!(invalid.starts_with("the server key is the public default"))
+      }
+      other => panic!("gave {:?}", other),
+    }
+  }
+}
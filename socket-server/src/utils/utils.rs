@@ -1,8 +1,11 @@
+use crate::utils::config::{DuplicateIdPolicy, OverflowPolicy, RateLimitAction};
 use base64::engine::general_purpose;
 use base64::Engine;
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
 use getset::Getters;
 use sha1::Digest;
+use std::net::IpAddr;
+use std::path::PathBuf;
 
 pub const WEBSOCKET_PREFIX: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
@@ -15,10 +18,67 @@ pub fn sec_websocket_key(client_key: String) -> String {
   my_key
 }
 
-#[derive(Debug, Getters)]
+// Command line overrides for the config file, anything left out keeps its configured value
+#[derive(Getters)]
 pub struct Opts {
   #[getset(get = "pub")]
-  threads: usize,
+  config_file: Option<PathBuf>,
+  #[getset(get = "pub")]
+  threads: Option<usize>,
+  #[getset(get = "pub")]
+  addresses: Option<Vec<IpAddr>>,
+  #[getset(get = "pub")]
+  port: Option<u16>,
+  #[getset(get = "pub")]
+  key: Option<String>,
+  #[getset(get = "pub")]
+  log_level: Option<String>,
+  #[getset(get = "pub")]
+  log_file: Option<PathBuf>,
+  #[getset(get = "pub")]
+  stats_interval: Option<u64>,
+  #[getset(get = "pub")]
+  max_message_size: Option<usize>,
+  #[getset(get = "pub")]
+  max_frame_size: Option<usize>,
+  #[getset(get = "pub")]
+  max_connections: Option<usize>,
+  #[getset(get = "pub")]
+  close_timeout: Option<u64>,
+  #[getset(get = "pub")]
+  heartbeat_interval: Option<u64>,
+  #[getset(get = "pub")]
+  max_missed_pongs: Option<u32>,
+  #[getset(get = "pub")]
+  tls_cert: Option<PathBuf>,
+  #[getset(get = "pub")]
+  tls_key: Option<PathBuf>,
+  #[getset(get = "pub")]
+  tls_client_ca: Option<PathBuf>,
+  #[getset(get = "pub")]
+  no_deflate: bool,
+  #[getset(get = "pub")]
+  protocols: Option<Vec<String>>,
+  #[getset(get = "pub")]
+  issue_token: Option<u32>,
+  #[getset(get = "pub")]
+  duplicate_ids: Option<DuplicateIdPolicy>,
+  #[getset(get = "pub")]
+  allow_default_key: bool,
+  #[getset(get = "pub")]
+  offline_queue: bool,
+  #[getset(get = "pub")]
+  queue_size: Option<usize>,
+  #[getset(get = "pub")]
+  overflow: Option<OverflowPolicy>,
+  #[getset(get = "pub")]
+  message_rate: Option<u32>,
+  #[getset(get = "pub")]
+  byte_rate: Option<u64>,
+  #[getset(get = "pub")]
+  rate_limit_action: Option<RateLimitAction>,
+  #[getset(get = "pub")]
+  connection_rate: Option<u32>,
 }
 
 impl Opts {
@@ -27,6 +87,16 @@ impl Opts {
       .version(env!("CARGO_PKG_VERSION"))
       .author(env!("CARGO_PKG_AUTHORS"))
       .about("Control the websocket server")
+      .arg(
+        Arg::new("config")
+          .short('f')
+          .long("config")
+          .env("SOCKET_SERVER_CONFIG")
+          .value_name("FILE")
+          .help("reads settings from a TOML file, other flags override it")
+          .required(false)
+          .num_args(1),
+      )
       .arg(
         Arg::new("num_threads")
           .short('t')
@@ -35,15 +105,361 @@ impl Opts {
           .help("sets the number of threads")
           .required(false)
           .num_args(1),
+      )
+      .arg(
+        Arg::new("address")
+          .short('a')
+          .long("address")
+          .value_name("IP")
+          .help("sets an IPv4 or IPv6 address to listen on, repeat or comma separate for several")
+          .required(false)
+          .action(ArgAction::Append)
+          .value_delimiter(','),
+      )
+      .arg(
+        Arg::new("port")
+          .short('p')
+          .long("port")
+          .value_name("PORT")
+          .help("sets the port to listen on, 0 picks a free one")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("key")
+          .short('k')
+          .long("key")
+          .env("SOCKET_SERVER_KEY")
+          .value_name("KEY")
+          .help("sets the server key")
+          .required(false)
+          .hide_env_values(true)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("debug")
+          .short('d')
+          .long("debug")
+          .help("turns on debug logging, same as --log_level debug")
+          .required(false)
+          .action(ArgAction::SetTrue)
+          .conflicts_with("log_level"),
+      )
+      .arg(
+        Arg::new("log_level")
+          .short('l')
+          .long("log_level")
+          .value_name("LEVEL")
+          .help("sets the log level: trace, debug, info, warn or error")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("log_file")
+          .long("log_file")
+          .value_name("FILE")
+          .help("appends the log to a file instead of printing it")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("stats_interval")
+          .long("stats_interval")
+          .value_name("MS")
+          .help("sets how often a line of server stats is logged, 0 turns it off")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("max_message_size")
+          .short('m')
+          .long("max_message_size")
+          .value_name("BYTES")
+          .help("sets the maximum size of a reassembled message")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("max_frame_size")
+          .long("max_frame_size")
+          .value_name("BYTES")
+          .help("sets the maximum payload size of a single frame")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("max_connections")
+          .long("max_connections")
+          .value_name("NUM")
+          .help("sets how many connections are served at once, 0 for no limit")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("close_timeout")
+          .short('c')
+          .long("close_timeout")
+          .value_name("MS")
+          .help("sets how long to wait for a client to answer a close frame")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("heartbeat_interval")
+          .long("heartbeat_interval")
+          .value_name("MS")
+          .help("sets how often clients are pinged, 0 turns the heartbeat off")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("max_missed_pongs")
+          .long("max_missed_pongs")
+          .value_name("NUM")
+          .help("sets how many pings a client can leave unanswered before it is dropped")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("tls_cert")
+          .long("tls_cert")
+          .value_name("FILE")
+          .help("serves wss with this PEM certificate chain, needs --tls_key")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("tls_key")
+          .long("tls_key")
+          .value_name("FILE")
+          .help("sets the PEM private key for --tls_cert")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("tls_client_ca")
+          .long("tls_client_ca")
+          .value_name("FILE")
+          .help("requires client certificates signed by a CA in this PEM file")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("no_deflate")
+          .long("no_deflate")
+          .help("turns off permessage-deflate compression")
+          .required(false)
+          .action(ArgAction::SetTrue),
+      )
+      .arg(
+        Arg::new("protocol")
+          .long("protocol")
+          .value_name("NAME")
+          .help("sets a subprotocol clients may ask for, repeat or comma separate for several")
+          .required(false)
+          .action(ArgAction::Append)
+          .value_delimiter(','),
+      )
+      .arg(
+        Arg::new("issue_token")
+          .long("issue_token")
+          .value_name("ID")
+          .help(
+            "prints a registration token for this client id signed with the server key and exits",
+          )
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("allow_default_key")
+          .long("allow_default_key")
+          .help("lets the server run with the public default key, for local testing only")
+          .required(false)
+          .action(ArgAction::SetTrue),
+      )
+      .arg(
+        Arg::new("duplicate_ids")
+          .long("duplicate_ids")
+          .value_name("POLICY")
+          .help(
+            "sets what happens when a connected id registers again: reject, replace or multiple",
+          )
+          .required(false)
+          .value_parser(["reject", "replace", "multiple"])
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("offline_queue")
+          .long("offline_queue")
+          .help("keeps messages for clients that disconnected until they register again")
+          .required(false)
+          .action(ArgAction::SetTrue),
+      )
+      .arg(
+        Arg::new("queue_size")
+          .long("queue_size")
+          .value_name("NUM")
+          .help("sets how many messages can wait to be written to each client")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("overflow")
+          .long("overflow")
+          .value_name("POLICY")
+          .help(
+            "sets what happens when a client's queue is full: drop_oldest, drop_newest or disconnect",
+          )
+          .required(false)
+          .value_parser(["drop_oldest", "drop_newest", "disconnect"])
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("message_rate")
+          .long("message_rate")
+          .value_name("NUM")
+          .help("limits how many messages per second each client ID can send")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("byte_rate")
+          .long("byte_rate")
+          .value_name("BYTES")
+          .help("limits how many bytes per second each client ID can send")
+          .required(false)
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("rate_limit_action")
+          .long("rate_limit_action")
+          .value_name("ACTION")
+          .help("sets what happens to messages over the rate: drop, delay or close")
+          .required(false)
+          .value_parser(["drop", "delay", "close"])
+          .num_args(1),
+      )
+      .arg(
+        Arg::new("connection_rate")
+          .long("connection_rate")
+          .value_name("NUM")
+          .help("limits how many connection attempts per minute each address can make")
+          .required(false)
+          .num_args(1),
       );
     let matches = app.get_matches();
-    let num_cpus: &String = &std::thread::available_parallelism()
-      .unwrap()
-      .get()
-      .to_string();
-    let threads_str: &String = matches.get_one("num_threads").unwrap_or(num_cpus);
-    let threads: usize = threads_str.parse::<usize>().unwrap();
-    let opts = Opts { threads };
+    let config_file: Option<PathBuf> = matches.get_one::<String>("config").map(PathBuf::from);
+    let threads: Option<usize> = matches
+      .get_one::<String>("num_threads")
+      .map(|threads| threads.parse::<usize>().unwrap());
+    let addresses: Option<Vec<IpAddr>> = matches.get_many::<String>("address").map(|addresses| {
+      addresses
+        .map(|address| {
+          address
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .expect("Invalid address")
+        })
+        .collect()
+    });
+    let port: Option<u16> = matches
+      .get_one::<String>("port")
+      .map(|port| port.parse::<u16>().unwrap());
+    let key: Option<String> = matches.get_one::<String>("key").cloned();
+    let log_level: Option<String> = if matches.get_flag("debug") {
+      Some(String::from("debug"))
+    } else {
+      matches.get_one::<String>("log_level").cloned()
+    };
+    let log_file: Option<PathBuf> = matches.get_one::<String>("log_file").map(PathBuf::from);
+    let stats_interval: Option<u64> = matches
+      .get_one::<String>("stats_interval")
+      .map(|interval| interval.parse::<u64>().unwrap());
+    let max_message_size: Option<usize> = matches
+      .get_one::<String>("max_message_size")
+      .map(|size| size.parse::<usize>().unwrap());
+    let max_frame_size: Option<usize> = matches
+      .get_one::<String>("max_frame_size")
+      .map(|size| size.parse::<usize>().unwrap());
+    let max_connections: Option<usize> = matches
+      .get_one::<String>("max_connections")
+      .map(|connections| connections.parse::<usize>().unwrap());
+    let close_timeout: Option<u64> = matches
+      .get_one::<String>("close_timeout")
+      .map(|timeout| timeout.parse::<u64>().unwrap());
+    let heartbeat_interval: Option<u64> = matches
+      .get_one::<String>("heartbeat_interval")
+      .map(|interval| interval.parse::<u64>().unwrap());
+    let max_missed_pongs: Option<u32> = matches
+      .get_one::<String>("max_missed_pongs")
+      .map(|pongs| pongs.parse::<u32>().unwrap());
+    let tls_cert: Option<PathBuf> = matches.get_one::<String>("tls_cert").map(PathBuf::from);
+    let tls_key: Option<PathBuf> = matches.get_one::<String>("tls_key").map(PathBuf::from);
+    let tls_client_ca: Option<PathBuf> = matches
+      .get_one::<String>("tls_client_ca")
+      .map(PathBuf::from);
+    let no_deflate: bool = matches.get_flag("no_deflate");
+    let protocols: Option<Vec<String>> = matches
+      .get_many::<String>("protocol")
+      .map(|protocols| protocols.cloned().collect());
+    let issue_token: Option<u32> = matches
+      .get_one::<String>("issue_token")
+      .map(|id| id.parse::<u32>().unwrap());
+    let allow_default_key: bool = matches.get_flag("allow_default_key");
+    let duplicate_ids: Option<DuplicateIdPolicy> = matches
+      .get_one::<String>("duplicate_ids")
+      .map(|policy| policy.parse::<DuplicateIdPolicy>().unwrap());
+    let offline_queue: bool = matches.get_flag("offline_queue");
+    let queue_size: Option<usize> = matches
+      .get_one::<String>("queue_size")
+      .map(|size| size.parse::<usize>().unwrap());
+    let overflow: Option<OverflowPolicy> = matches
+      .get_one::<String>("overflow")
+      .map(|policy| policy.parse::<OverflowPolicy>().unwrap());
+    let message_rate: Option<u32> = matches
+      .get_one::<String>("message_rate")
+      .map(|rate| rate.parse::<u32>().unwrap());
+    let byte_rate: Option<u64> = matches
+      .get_one::<String>("byte_rate")
+      .map(|rate| rate.parse::<u64>().unwrap());
+    let rate_limit_action: Option<RateLimitAction> = matches
+      .get_one::<String>("rate_limit_action")
+      .map(|action| action.parse::<RateLimitAction>().unwrap());
+    let connection_rate: Option<u32> = matches
+      .get_one::<String>("connection_rate")
+      .map(|rate| rate.parse::<u32>().unwrap());
+    let opts = Opts {
+      config_file,
+      threads,
+      addresses,
+      port,
+      key,
+      log_level,
+      log_file,
+      stats_interval,
+      max_message_size,
+      max_frame_size,
+      max_connections,
+      close_timeout,
+      heartbeat_interval,
+      max_missed_pongs,
+      tls_cert,
+      tls_key,
+      tls_client_ca,
+      no_deflate,
+      protocols,
+      issue_token,
+      duplicate_ids,
+      allow_default_key,
+      offline_queue,
+      queue_size,
+      overflow,
+      message_rate,
+      byte_rate,
+      rate_limit_action,
+      connection_rate,
+    };
     opts
   }
 }